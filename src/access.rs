@@ -0,0 +1,309 @@
+//! Reports on which recipients can decrypt which secrets
+
+use std::{collections::HashMap, fmt, path::Path, str::FromStr};
+
+use color_eyre::{
+    eyre::{eyre, Result},
+    Help,
+};
+
+use crate::{age, keys};
+
+/// Output format of an access matrix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MatrixFormat {
+    Csv,
+    Markdown,
+    /// Graphviz DOT graph with an edge from each recipient to each secret it can decrypt
+    Dot,
+}
+
+impl MatrixFormat {
+    pub(crate) const VARIANTS: [&'static str; 3] = ["csv", "markdown", "dot"];
+}
+
+impl FromStr for MatrixFormat {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "markdown" => Ok(Self::Markdown),
+            "dot" => Ok(Self::Dot),
+            _ => Err(eyre!("Unknown access matrix format: {s}"))
+                .with_suggestion(|| format!("Use one of {}", Self::VARIANTS.join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for MatrixFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Csv => "csv",
+            Self::Markdown => "markdown",
+            Self::Dot => "dot",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Resolve the recipients denoted by a command line argument.
+///
+/// The argument is either a recipient, a public key file or an identity file from which the
+/// recipients are derived. The recipients are normalized.
+pub(crate) fn resolve_recipients(arg: &str) -> Result<Vec<String>> {
+    let path = Path::new(arg);
+    if !path.is_file() {
+        age::validate_recipient(arg)?;
+        return Ok(vec![keys::normalize_recipient(arg)]);
+    }
+
+    let public_keys = keys::read_public_keys(path)?;
+    let recipients = if public_keys.is_empty() {
+        keys::read_identity_recipients(path)?
+    } else {
+        public_keys.into_iter().map(|pk| pk.recipient).collect()
+    };
+    if recipients.is_empty() {
+        return Err(eyre!("No recipients found in {arg}"));
+    }
+    Ok(recipients
+        .iter()
+        .map(|r| keys::normalize_recipient(r))
+        .collect())
+}
+
+/// Which recipients can decrypt which secrets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AccessMatrix {
+    pub secrets: Vec<String>,
+    /// Normalized recipients in order of their first appearance
+    pub recipients: Vec<String>,
+    /// Human-readable names of recipients, e.g., their `let` binding in the rules file
+    pub labels: HashMap<String, String>,
+    /// Indices into `recipients` for each secret
+    pub grants: Vec<Vec<usize>>,
+}
+
+impl AccessMatrix {
+    /// Build the matrix from pairs of secret names and their recipients
+    pub(crate) fn new<'a>(
+        rules: impl IntoIterator<Item = (String, &'a [String])>,
+        labels: HashMap<String, String>,
+    ) -> Self {
+        let mut secrets = Vec::new();
+        let mut recipients: Vec<String> = Vec::new();
+        let mut grants = Vec::new();
+        for (secret, public_keys) in rules {
+            let mut granted = Vec::new();
+            for recipient in public_keys.iter().map(|pk| keys::normalize_recipient(pk)) {
+                let index = if let Some(index) = recipients.iter().position(|r| *r == recipient) {
+                    index
+                } else {
+                    recipients.push(recipient);
+                    recipients.len() - 1
+                };
+                if !granted.contains(&index) {
+                    granted.push(index);
+                }
+            }
+            secrets.push(secret);
+            grants.push(granted);
+        }
+
+        Self {
+            secrets,
+            recipients,
+            labels,
+            grants,
+        }
+    }
+
+    fn label(&self, recipient: &str) -> String {
+        self.labels
+            .get(recipient)
+            .cloned()
+            .unwrap_or_else(|| recipient.to_string())
+    }
+
+    pub(crate) fn render(&self, format: MatrixFormat) -> String {
+        match format {
+            MatrixFormat::Csv => self.render_table(",", "", "", None, csv_field),
+            MatrixFormat::Markdown => {
+                self.render_table(" | ", "| ", " |", Some("---"), |s: &str| {
+                    s.replace('|', "\\|")
+                })
+            }
+            MatrixFormat::Dot => self.render_dot(),
+        }
+    }
+
+    fn render_table(
+        &self,
+        separator: &str,
+        start: &str,
+        end: &str,
+        header_rule: Option<&str>,
+        escape: impl Fn(&str) -> String,
+    ) -> String {
+        let mut lines = Vec::new();
+        let row = |cells: Vec<String>| format!("{start}{}{end}", cells.join(separator));
+
+        let mut header = vec![escape("secret")];
+        header.extend(self.recipients.iter().map(|r| escape(&self.label(r))));
+        let columns = header.len();
+        lines.push(row(header));
+        if let Some(rule) = header_rule {
+            lines.push(row(vec![rule.to_string(); columns]));
+        }
+        for (secret, granted) in self.secrets.iter().zip(&self.grants) {
+            let mut cells = vec![escape(secret)];
+            cells.extend(
+                (0..self.recipients.len())
+                    .map(|i| if granted.contains(&i) { "x" } else { "" }.to_string()),
+            );
+            lines.push(row(cells));
+        }
+
+        lines.join("\n") + "\n"
+    }
+
+    /// Nodes are identified by their index, as labels of secrets and recipients may coincide
+    fn render_dot(&self) -> String {
+        let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+        let mut lines = vec!["digraph access {".to_string(), "  rankdir=LR;".to_string()];
+        for (i, recipient) in self.recipients.iter().enumerate() {
+            lines.push(format!(
+                "  r{i} [label={}, shape=box];",
+                quote(&self.label(recipient))
+            ));
+        }
+        for (j, (secret, granted)) in self.secrets.iter().zip(&self.grants).enumerate() {
+            lines.push(format!("  s{j} [label={}];", quote(secret)));
+            for &i in granted {
+                lines.push(format!("  r{i} -> s{j};"));
+            }
+        }
+        lines.push("}".to_string());
+
+        lines.join("\n") + "\n"
+    }
+}
+
+/// Quote a CSV field if necessary
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test_access {
+    use indoc::indoc;
+
+    use super::*;
+
+    const SSH_ED25519: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILoPdkEfhcsmW6Lg86GMrEJZnYfFBb7fL9G/IXK7pDQd";
+    const AGE: &str = "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk";
+
+    fn matrix() -> AccessMatrix {
+        let a = [format!("{SSH_ED25519} wurzel@pfropf"), AGE.to_string()];
+        let b = [SSH_ED25519.to_string()];
+        AccessMatrix::new(
+            [
+                ("a.age".to_string(), &a[..]),
+                ("b,c.age".to_string(), &b[..]),
+            ],
+            HashMap::from([(SSH_ED25519.to_string(), "wurzel".to_string())]),
+        )
+    }
+
+    #[test]
+    fn normalizes_recipients() {
+        let matrix = matrix();
+        assert_eq!(matrix.recipients, [SSH_ED25519, AGE]);
+        assert_eq!(matrix.grants, [vec![0, 1], vec![0]]);
+    }
+
+    #[test]
+    fn renders_csv() {
+        assert_eq!(
+            matrix().render(MatrixFormat::Csv),
+            format!("secret,wurzel,{AGE}\na.age,x,x\n\"b,c.age\",x,\n")
+        );
+    }
+
+    #[test]
+    fn renders_markdown() {
+        assert_eq!(
+            matrix().render(MatrixFormat::Markdown),
+            format!(
+                "| secret | wurzel | {AGE} |\n| --- | --- | --- |\n| a.age | x | x |\n| b,c.age | x |  |\n"
+            )
+        );
+    }
+
+    #[test]
+    fn renders_dot() {
+        assert_eq!(
+            matrix().render(MatrixFormat::Dot),
+            format!(
+                indoc! {r#"
+                    digraph access {{
+                      rankdir=LR;
+                      r0 [label="wurzel", shape=box];
+                      r1 [label="{AGE}", shape=box];
+                      s0 [label="a.age"];
+                      r0 -> s0;
+                      r1 -> s0;
+                      s1 [label="b,c.age"];
+                      r0 -> s1;
+                    }}
+                "#},
+                AGE = AGE
+            )
+        );
+    }
+
+    #[test]
+    fn keeps_dot_nodes_with_same_label_apart() {
+        let recipients = [SSH_ED25519.to_string()];
+        let matrix = AccessMatrix::new(
+            [("wurzel".to_string(), &recipients[..])],
+            HashMap::from([(SSH_ED25519.to_string(), "wurzel".to_string())]),
+        );
+        assert_eq!(
+            matrix.render(MatrixFormat::Dot),
+            indoc! {r#"
+                digraph access {
+                  rankdir=LR;
+                  r0 [label="wurzel", shape=box];
+                  s0 [label="wurzel"];
+                  r0 -> s0;
+                }
+            "#}
+        );
+    }
+
+    #[test]
+    fn resolves_recipients() -> Result<()> {
+        let base_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("example/keys");
+        assert_eq!(
+            resolve_recipients(base_dir.join("id_ed25519").to_str().unwrap())?,
+            [SSH_ED25519]
+        );
+        assert_eq!(
+            resolve_recipients(base_dir.join("key.txt").to_str().unwrap())?,
+            [AGE]
+        );
+        assert_eq!(
+            resolve_recipients(&format!("{SSH_ED25519} wurzel@pfropf"))?,
+            [SSH_ED25519]
+        );
+        assert!(resolve_recipients("wurzelpfropf").is_err());
+        Ok(())
+    }
+}
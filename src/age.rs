@@ -3,7 +3,7 @@
 use std::{
     convert::Into,
     fs,
-    io::{self, BufReader},
+    io::{self, BufReader, Read, Write},
     path::Path,
 };
 
@@ -14,6 +14,8 @@ use age::{
         StdinGuard,
     },
     decryptor::RecipientsDecryptor,
+    secrecy::{ExposeSecret, SecretString, SecretVec},
+    ssh::ParseRecipientKeyError,
 };
 
 use color_eyre::{
@@ -23,6 +25,8 @@ use color_eyre::{
 
 use tempfile::NamedTempFile;
 
+use crate::util;
+
 fn get_age_decryptor<P: AsRef<Path>>(
     path: P,
 ) -> Result<RecipientsDecryptor<ArmoredReader<BufReader<InputReader>>>> {
@@ -41,6 +45,7 @@ fn get_age_decryptor<P: AsRef<Path>>(
 /// Parses a recipient from a string.
 /// [Copied from str4d/rage (ASL-2.0)](
 /// https://github.com/str4d/rage/blob/85c0788dc511f1410b4c1811be6b8904d91f85db/rage/src/bin/rage/main.rs)
+#[allow(clippy::block_scrutinee)] // Kept as in rage
 fn parse_recipient(
     s: &str,
     recipients: &mut Vec<Box<dyn age::Recipient + Send>>,
@@ -56,9 +61,47 @@ fn parse_recipient(
         plugin_recipients.push(pk);
         Ok(())
     } else {
-        Err(eyre!("Invalid recipient: {}", s))
-            .with_suggestion(|| "Make sure you use an ssh-ed25519, ssh-rsa or an X25519 public key, alternatively install an age plugin which supports your key")
+        Err(eyre!("Invalid recipient: {}", s)).with_suggestion(|| RECIPIENT_HINT)
+    }
+}
+
+/// How to fix an unusable recipient
+pub(crate) const RECIPIENT_HINT: &str = "Make sure you use an ssh-ed25519, ssh-rsa or an X25519 public key, alternatively install an age plugin which supports your key";
+
+/// Why `s` isn't a usable recipient, or `None` if it is.
+///
+/// If `plugins` is set, the plugin of a plugin recipient has to be installed.
+pub(crate) fn recipient_problem(s: &str, plugins: bool) -> Option<String> {
+    let x25519 = s.parse::<age::x25519::Recipient>();
+    if x25519.is_ok() {
+        return None;
+    }
+    if let Ok(pk) = s.parse::<age::plugin::Recipient>() {
+        let binary = format!("age-plugin-{}", pk.plugin());
+        return (plugins && util::find_executable(&binary).is_none())
+            .then(|| format!("unknown plugin {}, {binary} is not in PATH", pk.plugin()));
     }
+    match s.parse::<age::ssh::Recipient>() {
+        Ok(_) => None,
+        Err(ParseRecipientKeyError::Unsupported(key_type)) => {
+            Some(format!("unsupported SSH key type {key_type}"))
+        }
+        Err(ParseRecipientKeyError::RsaModulusTooSmall) => {
+            Some("ssh-rsa key with a modulus of less than 2048 bits".to_string())
+        }
+        Err(ParseRecipientKeyError::RsaModulusTooLarge) => {
+            Some("ssh-rsa key with a modulus of more than 4096 bits".to_string())
+        }
+        Err(_) if s.starts_with("age1") => x25519
+            .err()
+            .map(|reason| format!("invalid age recipient, {}", reason.to_lowercase())),
+        Err(_) => Some("neither an age recipient nor an SSH public key".to_string()),
+    }
+}
+
+/// Checks whether a string is a valid recipient.
+pub(crate) fn validate_recipient(s: &str) -> Result<()> {
+    parse_recipient(s, &mut Vec::new(), &mut Vec::new())
 }
 
 /// Returns the file paths to `$HOME/.ssh/{id_rsa,id_ed25519}` if each exists
@@ -106,15 +149,22 @@ fn merge_plugin_recipients_and_recipients(
     Ok(())
 }
 
-/// Get all the identities from the given paths and the default locations.
-///
-/// Default locations are `$HOME/.ssh/id_rsa` and `$HOME/.ssh/id_ed25519`.
-pub(crate) fn get_identities(identity_paths: &[String]) -> Result<Vec<Box<dyn age::Identity>>> {
+/// Get the given identity paths followed by the default locations which exist.
+pub(crate) fn get_identity_paths(identity_paths: &[String]) -> Result<Vec<String>> {
     let mut identities: Vec<String> = identity_paths.to_vec();
     let mut default_identities = get_default_identity_paths()?;
 
     identities.append(&mut default_identities);
 
+    Ok(identities)
+}
+
+/// Get all the identities from the given paths and the default locations.
+///
+/// Default locations are `$HOME/.ssh/id_rsa` and `$HOME/.ssh/id_ed25519`.
+pub(crate) fn get_identities(identity_paths: &[String]) -> Result<Vec<Box<dyn age::Identity>>> {
+    let identities = get_identity_paths(identity_paths)?;
+
     if identities.is_empty() {
         Err(eyre!("No usable identity or identities"))
     } else {
@@ -128,6 +178,53 @@ pub(crate) fn get_identities(identity_paths: &[String]) -> Result<Vec<Box<dyn ag
     }
 }
 
+/// Generate a new X25519 identity and write it to a file.
+///
+/// The identity file is created with a mode of `0o600` and must not exist yet. If a passphrase
+/// is given, the identity file is encrypted with it using an ASCII-armored format.
+pub(crate) fn generate_identity<P: AsRef<Path>>(
+    output_file: P,
+    passphrase: Option<SecretString>,
+) -> Result<age::x25519::Recipient> {
+    let identity = age::x25519::Identity::generate();
+    let recipient = identity.to_public();
+    let contents = format!(
+        "# public key: {recipient}\n{}\n",
+        identity.to_string().expose_secret()
+    );
+
+    // Temporary files are created with a mode of `0o600`. Writing to one next to the output
+    // leaves no partial identity behind on failure.
+    let output_file = output_file.as_ref();
+    let dir = output_file
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let mut output = NamedTempFile::new_in(dir)
+        .wrap_err_with(|| format!("Failed to create {}", output_file.display()))?;
+
+    if let Some(passphrase) = passphrase {
+        let encryptor = age::Encryptor::with_user_passphrase(passphrase);
+        let mut writer = encryptor
+            .wrap_output(
+                ArmoredWriter::wrap_output(output.as_file_mut(), Format::AsciiArmor)
+                    .wrap_err("Failed to wrap output with age::ArmoredWriter")?,
+            )
+            .map_err(|err| eyre!(err))?;
+        writer.write_all(contents.as_bytes())?;
+        writer.finish().and_then(ArmoredWriter::finish)?;
+    } else {
+        output.write_all(contents.as_bytes())?;
+    }
+
+    output
+        .persist_noclobber(output_file)
+        .map_err(|err| err.error)
+        .wrap_err_with(|| format!("Failed to create {}", output_file.display()))?;
+
+    Ok(recipient)
+}
+
 /// Decrypt an age-encrypted file to a plaintext file.
 ///
 /// The output file is created with a mode of `0o600`.
@@ -153,6 +250,21 @@ pub(crate) fn decrypt<P: AsRef<Path>>(
         })
 }
 
+/// Decrypt an age-encrypted file into memory.
+///
+/// Plaintext is never written to persistent storage.
+pub(crate) fn decrypt_to_memory<P: AsRef<Path>>(
+    input_file: P,
+    identities: &[Box<dyn age::Identity>],
+) -> Result<SecretVec<u8>> {
+    let decryptor = get_age_decryptor(input_file)?;
+    let mut plaintext_reader =
+        decryptor.decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))?;
+    let mut plaintext = vec![];
+    plaintext_reader.read_to_end(&mut plaintext)?;
+    Ok(SecretVec::new(plaintext))
+}
+
 /// Encrypt a plaintext file to an age-encrypted file.
 ///
 /// The output file is created with a mode of `0o644`.
@@ -246,3 +358,89 @@ pub(crate) fn rekey<P: AsRef<Path>>(
             Ok(())
         })
 }
+
+#[cfg(test)]
+mod test_recipients {
+    use bech32::{ToBase32, Variant};
+
+    use super::*;
+
+    #[test]
+    fn explains_invalid_recipients() -> Result<()> {
+        let plugin_recipient = bech32::encode("age1wurzel", [0u8; 8].to_base32(), Variant::Bech32)?;
+        assert_eq!(
+            recipient_problem(
+                "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk",
+                true
+            ),
+            None
+        );
+        assert_eq!(recipient_problem(&plugin_recipient, false), None);
+        assert_eq!(
+            recipient_problem(&plugin_recipient, true).as_deref(),
+            Some("unknown plugin wurzel, age-plugin-wurzel is not in PATH")
+        );
+        assert_eq!(
+            recipient_problem("age1wurzelpfropf", true).as_deref(),
+            Some("invalid age recipient, invalid bech32 encoding")
+        );
+        assert_eq!(
+            recipient_problem("ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBAIr3+4g9Cg/gBsz81k48kpbPm5FUk5N3OjQ50uRVxW8gm2doKx51bpYHNod01scSNKDqBZWjVNVMmwgUphmKZo=", true).as_deref(),
+            Some("unsupported SSH key type ecdsa-sha2-nistp256")
+        );
+        assert_eq!(
+            recipient_problem("wurzelpfropf", true).as_deref(),
+            Some("neither an age recipient nor an SSH public key")
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_identity {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn generates_passphrase_protected_identity() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("identity.age");
+        let passphrase = "wurzelpfropf";
+        let recipient = generate_identity(&path, Some(SecretString::new(passphrase.into())))?;
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+
+        // The identity is recognized as encrypted and only unlocked when used
+        let identities = get_identities(&[path.to_string_lossy().to_string()])?;
+        assert!(!identities.is_empty());
+
+        let age::Decryptor::Passphrase(decryptor) =
+            age::Decryptor::new(ArmoredReader::new(fs::File::open(&path)?))?
+        else {
+            panic!("identity is not passphrase-protected");
+        };
+        let mut contents = String::new();
+        decryptor
+            .decrypt(&SecretString::new(passphrase.into()), None)?
+            .read_to_string(&mut contents)?;
+        let identity = contents
+            .lines()
+            .find(|line| line.starts_with("AGE-SECRET-KEY-1"))
+            .ok_or_else(|| eyre!("no identity in {contents}"))?
+            .parse::<age::x25519::Identity>()
+            .map_err(|err| eyre!(err))?;
+        assert_eq!(identity.to_public().to_string(), recipient.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_existing_identity() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("identity.txt");
+        fs::write(&path, "precious\n")?;
+        assert!(generate_identity(&path, None).is_err());
+        assert_eq!(fs::read_to_string(&path)?, "precious\n");
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+//! Cache of the JSON the rules files evaluate to, kept in the user cache directory
+
+use std::{
+    env, fs,
+    io::Write,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use base64::{
+    engine::general_purpose::{
+        STANDARD as BASE64_STANDARD, URL_SAFE_NO_PAD as BASE64_URL_SAFE_NO_PAD,
+    },
+    Engine,
+};
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{nix, util};
+
+/// What an evaluation depends on besides the files it imports
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Key {
+    /// The arguments passed to `nix eval` like `--arg`
+    pub nix_args: Vec<String>,
+    /// The canonical path of the evaluator with the size and modification time of the binary,
+    /// which change with its version without having to run it
+    pub evaluator: String,
+    /// The search path for lookups like `<nixpkgs>`
+    pub nix_path: Option<String>,
+}
+
+impl Key {
+    /// The key of evaluations with the installed [`nix::Settings`]
+    pub(crate) fn current() -> Self {
+        let evaluator = nix::Evaluator::current();
+        // A bare name is looked up in `PATH`
+        let path = Some(evaluator.path())
+            .filter(|path| path.components().count() == 1)
+            .and_then(|path| util::find_executable(&path.to_string_lossy()))
+            .unwrap_or_else(|| evaluator.path().to_path_buf());
+        let path = fs::canonicalize(&path).unwrap_or(path);
+        let evaluator = match fs::metadata(&path) {
+            Ok(metadata) => format!(
+                "{} ({} bytes, modified {}.{:09})",
+                path.display(),
+                metadata.len(),
+                metadata.mtime(),
+                metadata.mtime_nsec()
+            ),
+            Err(_) => path.display().to_string(),
+        };
+        Self {
+            nix_args: nix::Settings::current().to_args(),
+            evaluator,
+            nix_path: env::var("NIX_PATH").ok(),
+        }
+    }
+}
+
+/// A cached evaluation of a rules file
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// The rules file and the Nix files it imports, each with the base64 SHA-256 of its contents
+    files: Vec<(PathBuf, String)>,
+    key: Key,
+    rules: serde_json::Value,
+}
+
+/// The directory holding one entry per rules file
+#[derive(Debug, Clone)]
+pub(crate) struct Cache {
+    dir: PathBuf,
+}
+
+/// The base64 SHA-256 of the contents of the file at `path`
+fn file_hash(path: &Path) -> Result<String> {
+    Ok(BASE64_STANDARD.encode(util::sha256(path)?))
+}
+
+impl Cache {
+    pub(crate) fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// The user cache, `$XDG_CACHE_HOME/ragenix`
+    pub(crate) fn user() -> Option<Self> {
+        let cache_home = env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+            .or_else(|| home::home_dir().map(|home| home.join(".cache")))?;
+        Some(Self::new(cache_home.join("ragenix")))
+    }
+
+    /// The path of the entry of the rules file at the canonical `rules_path`
+    fn entry_path(&self, rules_path: &Path) -> PathBuf {
+        let digest = Sha256::digest(rules_path.as_os_str().as_encoded_bytes());
+        self.dir
+            .join(format!("{}.json", BASE64_URL_SAFE_NO_PAD.encode(digest)))
+    }
+
+    /// The cached rules of the file at the canonical `rules_path` if neither the file nor its
+    /// imports changed since they were stored with the same `key`
+    pub(crate) fn lookup(&self, rules_path: &Path, key: &Key) -> Option<serde_json::Value> {
+        let contents = fs::read_to_string(self.entry_path(rules_path)).ok()?;
+        let entry: Entry = serde_json::from_str(&contents).ok()?;
+        (entry.key == *key
+            && entry
+                .files
+                .iter()
+                .all(|(path, hash)| file_hash(path).is_ok_and(|current| current == *hash)))
+        .then_some(entry.rules)
+    }
+
+    /// Store the rules of the file at the canonical `rules_path` evaluated with `key` which
+    /// imports `files`
+    pub(crate) fn store(
+        &self,
+        rules_path: &Path,
+        key: &Key,
+        files: &[PathBuf],
+        rules: &serde_json::Value,
+    ) -> Result<()> {
+        let mut paths = vec![rules_path.to_path_buf()];
+        paths.extend(files.iter().filter(|path| *path != rules_path).cloned());
+        let entry = Entry {
+            files: paths
+                .into_iter()
+                .map(|path| file_hash(&path).map(|hash| (path, hash)))
+                .collect::<Result<_>>()?,
+            key: key.clone(),
+            rules: rules.clone(),
+        };
+
+        // Replace the entry atomically so that concurrent invocations never read a partial entry
+        fs::create_dir_all(&self.dir)?;
+        let mut file = tempfile::NamedTempFile::new_in(&self.dir)?;
+        file.write_all(serde_json::to_string(&entry)?.as_bytes())?;
+        file.persist(self.entry_path(rules_path))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_cache {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn invalidates_changed_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = Cache::new(dir.path().join("cache"));
+        let rules_path = dir.path().join("secrets.nix");
+        let keys_path = dir.path().join("keys.nix");
+        fs::write(&rules_path, "let keys = import ./keys.nix; in { }")?;
+        fs::write(&keys_path, "[ ]")?;
+        let rules = json!({ "secret.age": { "publicKeys": [] } });
+        let key = Key {
+            evaluator: "/nix/store/aaaa-nix-2.18.1/bin/nix (1024 bytes, modified 1.000000000)"
+                .to_string(),
+            ..Key::default()
+        };
+
+        assert_eq!(cache.lookup(&rules_path, &key), None);
+        cache.store(
+            &rules_path,
+            &key,
+            &[rules_path.clone(), keys_path.clone()],
+            &rules,
+        )?;
+        assert_eq!(cache.lookup(&rules_path, &key), Some(rules.clone()));
+
+        fs::write(&keys_path, "[ \"age1\" ]")?;
+        assert_eq!(cache.lookup(&rules_path, &key), None);
+        cache.store(&rules_path, &key, std::slice::from_ref(&keys_path), &rules)?;
+        assert_eq!(cache.lookup(&rules_path, &key), Some(rules.clone()));
+        for other in [
+            Key {
+                nix_args: ["--argstr", "host", "wurzel"].map(String::from).to_vec(),
+                ..key.clone()
+            },
+            Key {
+                evaluator: "/nix/store/bbbb-nix-2.24.9/bin/nix (1024 bytes, modified 1.000000000)"
+                    .to_string(),
+                ..key.clone()
+            },
+            Key {
+                nix_path: Some("nixpkgs=/home/wurzel/nixpkgs".to_string()),
+                ..key.clone()
+            },
+        ] {
+            assert_eq!(cache.lookup(&rules_path, &other), None);
+        }
+
+        fs::write(&rules_path, "{ }")?;
+        assert_eq!(cache.lookup(&rules_path, &key), None);
+        Ok(())
+    }
+}
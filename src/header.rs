@@ -0,0 +1,406 @@
+//! Parsing of age file headers without decrypting the file
+
+use std::{fmt, fs, path::Path};
+
+use base64::{
+    engine::general_purpose::{STANDARD as BASE64_STANDARD, STANDARD_NO_PAD as BASE64_NO_PAD},
+    Engine,
+};
+use color_eyre::{
+    eyre::{eyre, Result, WrapErr},
+    Help,
+};
+use sha2::{Digest, Sha256};
+
+const VERSION_LINE: &str = "age-encryption.org/v1";
+const ARMOR_BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+const ARMOR_END: &str = "-----END AGE ENCRYPTED FILE-----";
+const STANZA_LINE_LENGTH: usize = 64;
+const PAYLOAD_NONCE_SIZE: u64 = 16;
+const CHUNK_SIZE: u64 = 64 * 1024;
+const TAG_SIZE: u64 = 16;
+
+/// The type of recipient a stanza was created for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum StanzaKind {
+    X25519,
+    /// ssh-ed25519 recipient with its key tag
+    SshEd25519(String),
+    /// ssh-rsa recipient with its key tag
+    SshRsa(String),
+    /// Passphrase
+    Scrypt,
+    /// Random stanza without any recipient which age adds to keep parsers tolerant
+    Grease,
+    /// Stanza of a plugin, usually named after the plugin
+    Plugin(String),
+}
+
+impl fmt::Display for StanzaKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::X25519 => f.write_str("X25519"),
+            Self::SshEd25519(tag) => write!(f, "ssh-ed25519 (key tag {tag})"),
+            Self::SshRsa(tag) => write!(f, "ssh-rsa (key tag {tag})"),
+            Self::Scrypt => f.write_str("scrypt (passphrase)"),
+            Self::Grease => f.write_str("grease"),
+            Self::Plugin(name) => write!(f, "plugin {name}"),
+        }
+    }
+}
+
+/// A recipient stanza of an age header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Stanza {
+    pub tag: String,
+    pub args: Vec<String>,
+}
+
+impl Stanza {
+    pub(crate) fn kind(&self) -> StanzaKind {
+        let first_arg = || self.args.first().cloned().unwrap_or_default();
+        match self.tag.as_str() {
+            "X25519" => StanzaKind::X25519,
+            "ssh-ed25519" => StanzaKind::SshEd25519(first_arg()),
+            "ssh-rsa" => StanzaKind::SshRsa(first_arg()),
+            "scrypt" => StanzaKind::Scrypt,
+            tag if tag.ends_with("-grease") => StanzaKind::Grease,
+            tag => StanzaKind::Plugin(tag.to_string()),
+        }
+    }
+}
+
+/// The header of an age-encrypted file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub armored: bool,
+    pub stanzas: Vec<Stanza>,
+    /// Size of the encrypted payload following the header
+    pub payload_size: u64,
+}
+
+impl Header {
+    /// The stanzas which are not grease
+    pub(crate) fn recipient_stanzas(&self) -> impl Iterator<Item = &Stanza> {
+        self.stanzas
+            .iter()
+            .filter(|stanza| stanza.kind() != StanzaKind::Grease)
+    }
+
+    /// Size of the plaintext as determined from the payload size
+    pub(crate) fn plaintext_size(&self) -> Option<u64> {
+        let ciphertext = self.payload_size.checked_sub(PAYLOAD_NONCE_SIZE)?;
+        let chunks = ciphertext.div_ceil(CHUNK_SIZE + TAG_SIZE).max(1);
+        ciphertext.checked_sub(chunks * TAG_SIZE)
+    }
+
+    /// Parse the header of an armored or binary age file
+    pub(crate) fn parse(data: &[u8]) -> Result<Self> {
+        if data.starts_with(ARMOR_BEGIN.as_bytes()) {
+            let text = std::str::from_utf8(data).wrap_err("Invalid armored age file")?;
+            let body = text
+                .lines()
+                .map(str::trim)
+                .skip(1)
+                .take_while(|line| *line != ARMOR_END)
+                .collect::<String>();
+            let binary = BASE64_STANDARD
+                .decode(body)
+                .wrap_err("Invalid armored age file")?;
+            return Ok(Self {
+                armored: true,
+                ..Self::parse_binary(&binary)?
+            });
+        }
+        Self::parse_binary(data)
+    }
+
+    fn parse_binary(data: &[u8]) -> Result<Self> {
+        let mut rest = data;
+        let mut next_line = || -> Result<&str> {
+            let end = rest
+                .iter()
+                .position(|b| *b == b'\n')
+                .ok_or_else(|| eyre!("Unexpected end of age header"))?;
+            let line = std::str::from_utf8(&rest[..end]).wrap_err("Invalid age header")?;
+            rest = &rest[end + 1..];
+            Ok(line)
+        };
+
+        if next_line()? != VERSION_LINE {
+            return Err(eyre!("Not an age-encrypted file"))
+                .with_note(|| format!("Expected the header to start with {VERSION_LINE}"));
+        }
+
+        let mut stanzas = Vec::new();
+        loop {
+            let line = next_line()?;
+            if let Some(stanza) = line.strip_prefix("-> ") {
+                let mut args = stanza.split(' ').map(ToString::to_string);
+                let tag = args.next().unwrap_or_default();
+                stanzas.push(Stanza {
+                    tag,
+                    args: args.collect(),
+                });
+                // The body is wrapped at 64 columns and ends with a shorter line
+                while next_line()?.len() >= STANZA_LINE_LENGTH {}
+            } else if line.starts_with("---") {
+                break;
+            } else {
+                return Err(eyre!("Invalid age header line: {line}"));
+            }
+        }
+
+        Ok(Self {
+            armored: false,
+            stanzas,
+            payload_size: rest.len() as u64,
+        })
+    }
+
+    /// Read the header of the age file at `path`
+    pub(crate) fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let data = fs::read(&path)
+            .wrap_err_with(|| format!("Failed to read {}", path.as_ref().display()))?;
+        Self::parse(&data).wrap_err_with(|| format!("Invalid age file {}", path.as_ref().display()))
+    }
+}
+
+/// The key tag age writes to the stanza of an SSH recipient.
+///
+/// Returns `None` if `recipient` isn't an SSH public key.
+pub(crate) fn ssh_key_tag(recipient: &str) -> Option<String> {
+    let mut parts = recipient.split_whitespace();
+    let key_type = parts.next()?;
+    if key_type != "ssh-ed25519" && key_type != "ssh-rsa" {
+        return None;
+    }
+    let key = BASE64_STANDARD.decode(parts.next()?).ok()?;
+    Some(BASE64_NO_PAD.encode(&Sha256::digest(key)[..4]))
+}
+
+/// A difference between the header of a secret and the recipients of its rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Drift {
+    /// The secret is not encrypted to an SSH recipient of the rule
+    MissingSshRecipient(String),
+    /// The secret is encrypted to an SSH key with this tag which is not part of the rule
+    UnknownSshRecipient(String),
+    /// The number of X25519 stanzas differs from the number of age recipients of the rule
+    X25519Count { expected: usize, actual: usize },
+    /// The number of plugin stanzas differs from the number of plugin recipients of the rule
+    PluginCount { expected: usize, actual: usize },
+    /// The secret is encrypted with a passphrase
+    Passphrase,
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingSshRecipient(recipient) => write!(f, "not encrypted to {recipient}"),
+            Self::UnknownSshRecipient(tag) => {
+                write!(f, "encrypted to an SSH key not in the rule (key tag {tag})")
+            }
+            Self::X25519Count { expected, actual } => write!(
+                f,
+                "encrypted to {actual} X25519 recipient(s), the rule has {expected}"
+            ),
+            Self::PluginCount { expected, actual } => write!(
+                f,
+                "encrypted to {actual} plugin recipient(s), the rule has {expected}"
+            ),
+            Self::Passphrase => f.write_str("encrypted with a passphrase"),
+        }
+    }
+}
+
+impl Header {
+    /// Compare the stanzas with the recipients of a rule.
+    ///
+    /// SSH recipients are matched by their key tag. Stanzas of X25519 and plugin recipients don't
+    /// identify their recipient, so only their numbers are compared.
+    pub(crate) fn drift(&self, public_keys: &[String]) -> Vec<Drift> {
+        let mut ssh_tags = Vec::new();
+        let mut x25519 = 0;
+        let mut plugins = 0;
+        let mut drift = Vec::new();
+        for stanza in self.recipient_stanzas() {
+            match stanza.kind() {
+                StanzaKind::SshEd25519(tag) | StanzaKind::SshRsa(tag) => ssh_tags.push(tag),
+                StanzaKind::X25519 => x25519 += 1,
+                StanzaKind::Plugin(_) => plugins += 1,
+                StanzaKind::Scrypt => drift.push(Drift::Passphrase),
+                StanzaKind::Grease => {}
+            }
+        }
+
+        let mut expected_x25519 = 0;
+        let mut expected_plugins = 0;
+        for public_key in public_keys {
+            if let Some(tag) = ssh_key_tag(public_key) {
+                if let Some(i) = ssh_tags.iter().position(|t| *t == tag) {
+                    ssh_tags.swap_remove(i);
+                } else {
+                    drift.push(Drift::MissingSshRecipient(public_key.clone()));
+                }
+            } else if public_key.parse::<::age::x25519::Recipient>().is_ok() {
+                expected_x25519 += 1;
+            } else if public_key.parse::<::age::plugin::Recipient>().is_ok() {
+                expected_plugins += 1;
+            }
+        }
+        drift.extend(ssh_tags.into_iter().map(Drift::UnknownSshRecipient));
+        if x25519 != expected_x25519 {
+            drift.push(Drift::X25519Count {
+                expected: expected_x25519,
+                actual: x25519,
+            });
+        }
+        if plugins != expected_plugins {
+            drift.push(Drift::PluginCount {
+                expected: expected_plugins,
+                actual: plugins,
+            });
+        }
+        drift
+    }
+}
+
+#[cfg(test)]
+mod test_header {
+    use std::io::Write;
+
+    use indoc::indoc;
+
+    use super::*;
+
+    const SSH_ED25519: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILoPdkEfhcsmW6Lg86GMrEJZnYfFBb7fL9G/IXK7pDQd";
+
+    fn encrypt(recipients: Vec<Box<dyn ::age::Recipient + Send>>, plaintext: &[u8]) -> Vec<u8> {
+        let encryptor = ::age::Encryptor::with_recipients(recipients).unwrap();
+        let mut output = Vec::new();
+        let mut writer = encryptor.wrap_output(&mut output).unwrap();
+        writer.write_all(plaintext).unwrap();
+        writer.finish().unwrap();
+        output
+    }
+
+    #[test]
+    fn parses_stanzas() -> Result<()> {
+        let header = Header::parse(
+            indoc! {"
+                age-encryption.org/v1
+                -> X25519 CJM36AHmTbdHSuOQL+NESqyVQE75f2e610iRdLPEN20
+                C3ZAeY64NXS4QFrksLm3EGz+uPRyI0eQsWw7LWbbYig
+                -> ssh-ed25519 BTBNbg 3zPQT+bPEBOyIKLF9+sRfp6zmk8C1hXAR6hW6Cj+eVs
+                UbuA91rsVNzbxOdT0iB2ajHmOC+uPdKR0O4QRcAPb0a
+                -> piv-p256 N3bb8w A7JPpFfhwSW5oQbAp5jPsZw/TbnBiTlWG7shm1gxAZQ5
+                XK1lhMMGUYvmy7k9wOyDPVWUVdWhUMuFV3R1yPVUi58
+                -> 6-grease [M& ;Y
+                Uw7QaH6uS1dAMKnb3uxxYs4KbnBhABS5kBNKN3YD3gQTntSTjZ2Mzw
+                --- n4hbxLJ0nSSbBwUXmH5YFP6gY6ytw2yB5sdQVEEb/sc
+            "}
+            .as_bytes(),
+        )?;
+        let kinds = header.stanzas.iter().map(Stanza::kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                StanzaKind::X25519,
+                StanzaKind::SshEd25519("BTBNbg".to_string()),
+                StanzaKind::Plugin("piv-p256".to_string()),
+                StanzaKind::Grease,
+            ]
+        );
+        assert_eq!(header.recipient_stanzas().count(), 3);
+        assert!(!header.armored);
+        assert_eq!(header.payload_size, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn parses_encrypted_files() -> Result<()> {
+        let recipient = SSH_ED25519.parse::<::age::ssh::Recipient>().unwrap();
+        let plaintext = vec![0x42; 100_000];
+        let encrypted = encrypt(vec![Box::new(recipient)], &plaintext);
+
+        let header = Header::parse(&encrypted)?;
+        let ssh_stanzas = header
+            .recipient_stanzas()
+            .map(Stanza::kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ssh_stanzas,
+            [StanzaKind::SshEd25519(ssh_key_tag(SSH_ED25519).unwrap())]
+        );
+        assert_eq!(header.plaintext_size(), Some(100_000));
+
+        let empty = encrypt(
+            vec![Box::new(::age::x25519::Identity::generate().to_public())],
+            b"",
+        );
+        assert_eq!(Header::parse(&empty)?.plaintext_size(), Some(0));
+        Ok(())
+    }
+
+    #[test]
+    fn parses_armored_files() -> Result<()> {
+        let base_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let header = Header::read(base_dir.join("example/root.passwd.age"))?;
+        assert!(header.armored);
+        let kinds = header
+            .recipient_stanzas()
+            .map(Stanza::kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds.len(), 3);
+        assert!(kinds.contains(&StanzaKind::SshEd25519(ssh_key_tag(SSH_ED25519).unwrap())));
+        Ok(())
+    }
+
+    #[test]
+    fn detects_drift() -> Result<()> {
+        const AGE: &str = "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk";
+        const SSH_RSA_TAG: &str = "1NDNnA";
+        let base_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let header = Header::read(base_dir.join("example/root.passwd.age"))?;
+
+        assert_eq!(
+            header.drift(&[
+                AGE.to_string(),
+                format!("{SSH_ED25519} wurzel@pfropf"),
+                "age1unencrypted1k5fr0r".to_string()
+            ]),
+            [
+                Drift::UnknownSshRecipient(SSH_RSA_TAG.to_string()),
+                Drift::PluginCount {
+                    expected: 1,
+                    actual: 0
+                }
+            ]
+        );
+        assert_eq!(
+            header.drift(&[AGE.to_string()]),
+            [
+                Drift::UnknownSshRecipient(ssh_key_tag(SSH_ED25519).unwrap()),
+                Drift::UnknownSshRecipient(SSH_RSA_TAG.to_string()),
+            ]
+        );
+
+        let other_ssh_key =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHsKLqeplhpW+uObz5dvMgjz1OxfM/XXUB+VHtZ6isGN";
+        let drift = header.drift(&[other_ssh_key.to_string()]);
+        assert!(drift.contains(&Drift::MissingSshRecipient(other_ssh_key.to_string())));
+        assert!(drift.contains(&Drift::X25519Count {
+            expected: 0,
+            actual: 1
+        }));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(Header::parse(b"wurzelpfropf\n").is_err());
+        assert!(Header::parse(b"age-encryption.org/v1\n-> X25519 abc\n").is_err());
+    }
+}
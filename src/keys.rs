@@ -0,0 +1,461 @@
+//! Parsing of public keys and conversion between SSH keys and native age keys
+
+use std::{
+    fs,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use age::secrecy::{ExposeSecret, SecretString};
+use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine};
+use bech32::{Bech32Writer, ToBase32, Variant};
+use color_eyre::{
+    eyre::{eyre, Result, WrapErr},
+    Help,
+};
+use sha2::{Digest, Sha512};
+use zeroize::Zeroizing;
+
+const PUBLIC_KEY_PREFIX: &str = "age";
+const SECRET_KEY_PREFIX: &str = "age-secret-key-";
+
+/// A recipient read from a public key file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PublicKey {
+    /// The recipient without its comment
+    pub recipient: String,
+    pub comment: Option<String>,
+    pub source: PathBuf,
+}
+
+/// Read the recipients from a `.pub` or an `authorized_keys`-style file.
+///
+/// Each line may hold an ssh-ed25519, an ssh-rsa or an age recipient. Options preceding an SSH
+/// key, empty lines, comments and keys of unsupported types are skipped.
+pub(crate) fn read_public_keys<P: AsRef<Path>>(path: P) -> Result<Vec<PublicKey>> {
+    let contents = fs::read_to_string(&path)
+        .wrap_err_with(|| format!("Failed to read {}", path.as_ref().display()))?;
+
+    let mut keys = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        let key = tokens.iter().enumerate().find_map(|(i, token)| {
+            if token.starts_with("age1") {
+                let pk = token.parse::<age::x25519::Recipient>().ok()?;
+                Some((pk.to_string(), i + 1))
+            } else {
+                let candidate = tokens.get(i..i + 2)?.join(" ");
+                let pk = candidate.parse::<age::ssh::Recipient>().ok()?;
+                Some((pk.to_string(), i + 2))
+            }
+        });
+        if let Some((recipient, end)) = key {
+            let comment = Some(tokens[end..].join(" ")).filter(|c| !c.is_empty());
+            keys.push(PublicKey {
+                recipient,
+                comment,
+                source: path.as_ref().to_path_buf(),
+            });
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Normalize the spelling of a recipient.
+///
+/// SSH public keys are stripped of their comment and surrounding whitespace, age recipients are
+/// re-encoded. Recipients which can't be parsed, e.g., plugin recipients, are only trimmed.
+pub(crate) fn normalize_recipient(s: &str) -> String {
+    let s = s.trim();
+    if let Ok(pk) = s.parse::<age::ssh::Recipient>() {
+        pk.to_string()
+    } else if let Ok(pk) = s.parse::<age::x25519::Recipient>() {
+        pk.to_string()
+    } else {
+        s.to_string()
+    }
+}
+
+/// The modulus size in bits of an ssh-rsa public key.
+///
+/// Returns `None` if `recipient` isn't a valid ssh-rsa public key.
+pub(crate) fn ssh_rsa_bits(recipient: &str) -> Option<usize> {
+    fn read_string<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+        let len = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+        let value = data.get(4..4 + len)?;
+        *data = &data[4 + len..];
+        Some(value)
+    }
+
+    let mut parts = recipient.split_whitespace();
+    if parts.next()? != "ssh-rsa" {
+        return None;
+    }
+    let key = BASE64_STANDARD.decode(parts.next()?).ok()?;
+    let mut data = &key[..];
+    if read_string(&mut data)? != b"ssh-rsa" {
+        return None;
+    }
+    let _exponent = read_string(&mut data)?;
+    let modulus = read_string(&mut data)?;
+    let leading_zeros = modulus.iter().take_while(|b| **b == 0).count();
+    let modulus = &modulus[leading_zeros..];
+    Some(modulus.len() * 8 - modulus.first().map_or(0, |b| b.leading_zeros() as usize))
+}
+
+/// The recipients derived from an identity file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct IdentityRecipients {
+    pub recipients: Vec<String>,
+    /// The plugins of the plugin identities whose recipients aren't noted in a comment
+    pub unknown_plugins: Vec<String>,
+}
+
+/// Derive the recipients of the identities in an age identity file or an SSH private key.
+///
+/// See [`identity_recipients`] for which recipients can be derived.
+pub(crate) fn read_identity_recipients<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
+    Ok(identity_recipients(path)?.recipients)
+}
+
+/// Derive the recipients of the identities in an age identity file or an SSH private key.
+///
+/// Encrypted SSH private keys don't need to be decrypted as they contain their public key. The
+/// plugin protocol has no way to ask a plugin for the recipient of an identity, so the
+/// recipients of plugin identities are read from `# recipient:` or `# public key:` comments
+/// which plugins usually write to the identity file. Plugins of identities without such a
+/// comment are reported as unknown.
+pub(crate) fn identity_recipients<P: AsRef<Path>>(path: P) -> Result<IdentityRecipients> {
+    let contents =
+        fs::read(&path).wrap_err_with(|| format!("Failed to read {}", path.as_ref().display()))?;
+
+    if let Ok(identity) = age::ssh::Identity::from_buffer(BufReader::new(&contents[..]), None) {
+        let recipient = age::ssh::Recipient::try_from(identity)
+            .map_err(|err| eyre!("Unsupported SSH private key: {err:?}"))?;
+        return Ok(IdentityRecipients {
+            recipients: vec![recipient.to_string()],
+            ..IdentityRecipients::default()
+        });
+    }
+
+    let identity_file = age::IdentityFile::from_buffer(BufReader::new(&contents[..]))
+        .map_err(|_| {
+            eyre!(
+                "{} is neither an age identity file nor an SSH private key",
+                path.as_ref().display()
+            )
+        })
+        .with_suggestion(|| {
+            "Pass the public key instead, e.g., if the identity file is encrypted"
+        })?;
+    let mut recipients = Vec::new();
+    let mut plugins = Vec::new();
+    for entry in identity_file.into_identities() {
+        match entry {
+            age::IdentityFileEntry::Native(identity) => {
+                recipients.push(identity.to_public().to_string());
+            }
+            age::IdentityFileEntry::Plugin(identity) => plugins.push(identity.plugin().to_string()),
+        }
+    }
+    if plugins.is_empty() {
+        return Ok(IdentityRecipients {
+            recipients,
+            ..IdentityRecipients::default()
+        });
+    }
+
+    let commented = commented_recipients(&String::from_utf8_lossy(&contents));
+    let mut unknown_plugins = Vec::new();
+    for plugin in plugins {
+        let noted = commented.iter().any(|recipient| {
+            recipient
+                .parse::<age::plugin::Recipient>()
+                .is_ok_and(|recipient| recipient.plugin() == plugin)
+        });
+        if !noted && !unknown_plugins.contains(&plugin) {
+            unknown_plugins.push(plugin);
+        }
+    }
+    for recipient in commented {
+        if !recipients.contains(&recipient) {
+            recipients.push(recipient);
+        }
+    }
+    Ok(IdentityRecipients {
+        recipients,
+        unknown_plugins,
+    })
+}
+
+/// Recipients noted in `# recipient: ...` or `# public key: ...` comments of an identity file
+fn commented_recipients(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix('#'))
+        .filter_map(|comment| comment.split_once(':'))
+        .filter(|(key, _)| {
+            let key = key.trim().to_lowercase();
+            key == "recipient" || key == "public key"
+        })
+        .filter_map(|(_, value)| {
+            let value = value.trim();
+            if let Ok(pk) = value.parse::<age::x25519::Recipient>() {
+                Some(pk.to_string())
+            } else {
+                let pk = value.parse::<age::plugin::Recipient>().ok()?;
+                Some(pk.to_string())
+            }
+        })
+        .collect()
+}
+
+/// Returns the paths of the local SSH host and user public keys.
+///
+/// These are `/etc/ssh/ssh_host_*_key.pub` and `$HOME/.ssh/*.pub`.
+pub(crate) fn get_default_public_key_paths() -> Result<Vec<PathBuf>> {
+    fn list(dir: &Path, matches: impl Fn(&Path) -> bool) -> Vec<PathBuf> {
+        let mut paths = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(std::result::Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| matches(path))
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    let is_pub = |path: &Path| path.extension().is_some_and(|ext| ext == "pub");
+    let home_path = home::home_dir().ok_or_else(|| eyre!("Could not determine home directory"))?;
+    let mut paths = list(Path::new("/etc/ssh"), |path| {
+        is_pub(path)
+            && path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("ssh_host_") && name.ends_with("_key.pub"))
+    });
+    paths.append(&mut list(&home_path.join(".ssh"), is_pub));
+
+    Ok(paths)
+}
+
+/// Convert an ssh-ed25519 public key to the native age recipient of the same key.
+///
+/// The resulting recipient is the X25519 public key age uses internally when encrypting to the
+/// ssh-ed25519 recipient.
+pub(crate) fn ssh_to_age_recipient(s: &str) -> Result<age::x25519::Recipient> {
+    let recipient = s
+        .parse::<age::ssh::Recipient>()
+        .map_err(|err| eyre!("Invalid SSH public key: {err:?}"))?;
+    let age::ssh::Recipient::SshEd25519(_, point) = recipient else {
+        return Err(eyre!("Only ssh-ed25519 keys can be converted"))
+            .with_note(|| "ssh-rsa keys have no X25519 equivalent");
+    };
+
+    let encoded = bech32::encode(
+        PUBLIC_KEY_PREFIX,
+        point.to_montgomery().to_bytes().to_base32(),
+        Variant::Bech32,
+    )?;
+    encoded
+        .parse()
+        .map_err(|err| eyre!("Failed to convert SSH public key: {err}"))
+}
+
+/// Convert an OpenSSH ed25519 private key to the native age identity of the same key.
+///
+/// Calls `passphrase` if the private key is encrypted.
+pub(crate) fn ssh_to_age_identity(
+    s: &str,
+    passphrase: impl FnOnce() -> Result<SecretString>,
+) -> Result<age::x25519::Identity> {
+    let mut privkey =
+        ssh_key::PrivateKey::from_openssh(s).wrap_err("Invalid OpenSSH private key")?;
+    if privkey.is_encrypted() {
+        privkey = privkey
+            .decrypt(passphrase()?.expose_secret())
+            .map_err(|err| eyre!("Failed to decrypt OpenSSH private key: {err}"))?;
+    }
+    let Some(keypair) = privkey.key_data().ed25519() else {
+        return Err(eyre!("Only ssh-ed25519 keys can be converted"))
+            .with_note(|| "ssh-rsa keys have no X25519 equivalent");
+    };
+
+    // Same derivation as age's ssh-ed25519 identity, clamped like an X25519 secret. Every
+    // intermediate holding the secret is zeroized when dropped.
+    let mut digest = Zeroizing::new([0u8; 64]);
+    Sha512::new()
+        .chain_update(keypair.private.as_ref())
+        .finalize_into(sha2::digest::Output::<Sha512>::from_mut_slice(&mut *digest));
+    let mut secret = Zeroizing::new([0u8; 32]);
+    secret.copy_from_slice(&digest[..32]);
+    secret[0] &= 0b1111_1000;
+    secret[31] &= 0b0111_1111;
+    secret[31] |= 0b0100_0000;
+
+    // Encoded without intermediate buffers into a string which never needs to grow
+    let mut encoded = Zeroizing::new(String::with_capacity(128));
+    let mut writer = Bech32Writer::new(SECRET_KEY_PREFIX, Variant::Bech32, &mut *encoded)?;
+    secret.write_base32(&mut writer)?;
+    writer.finalize()?;
+    encoded.make_ascii_uppercase();
+    encoded
+        .parse()
+        .map_err(|err| eyre!("Failed to convert OpenSSH private key: {err}"))
+}
+
+#[cfg(test)]
+mod test_convert {
+    use std::{fs, path::Path};
+
+    use super::*;
+
+    // Public key of `example/keys/id_ed25519`
+    const SSH_ED25519: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILoPdkEfhcsmW6Lg86GMrEJZnYfFBb7fL9G/IXK7pDQd";
+
+    fn example_key(name: &str) -> Result<String> {
+        let base_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        Ok(fs::read_to_string(
+            base_dir.join("example/keys").join(name),
+        )?)
+    }
+
+    #[test]
+    fn converts_key_pair_consistently() -> Result<()> {
+        let recipient = ssh_to_age_recipient(SSH_ED25519)?;
+        let identity = ssh_to_age_identity(&example_key("id_ed25519")?, || unreachable!())?;
+        assert_eq!(identity.to_public().to_string(), recipient.to_string());
+        assert!(recipient.to_string().starts_with("age1"));
+        assert!(identity
+            .to_string()
+            .expose_secret()
+            .starts_with("AGE-SECRET-KEY-1"));
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_comment() -> Result<()> {
+        assert_eq!(
+            ssh_to_age_recipient(&format!("{SSH_ED25519} wurzel@pfropf"))?.to_string(),
+            ssh_to_age_recipient(SSH_ED25519)?.to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reads_identity_recipients() -> Result<()> {
+        let base_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("example/keys");
+        assert_eq!(
+            read_identity_recipients(base_dir.join("id_ed25519"))?,
+            [SSH_ED25519]
+        );
+        assert_eq!(
+            read_identity_recipients(base_dir.join("example_plugin_key.txt"))?,
+            ["age1unencrypted1k5fr0r"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reports_plugin_identities_without_recipient() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("plugin_key.txt");
+        fs::write(&path, "AGE-PLUGIN-UNENCRYPTED-1CR7ZD5\n")?;
+        assert_eq!(
+            identity_recipients(&path)?,
+            IdentityRecipients {
+                recipients: vec![],
+                unknown_plugins: vec!["unencrypted".to_string()],
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_rsa() -> Result<()> {
+        let Err(err) = ssh_to_age_identity(&example_key("id_rsa")?, || unreachable!()) else {
+            panic!("ssh-rsa key must not be converted");
+        };
+        assert_eq!(err.to_string(), "Only ssh-ed25519 keys can be converted");
+        assert!(ssh_to_age_recipient(
+            "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk"
+        )
+        .is_err());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_public_keys {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    const SSH_ED25519: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILoPdkEfhcsmW6Lg86GMrEJZnYfFBb7fL9G/IXK7pDQd";
+    const AGE: &str = "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk";
+    const SSH_RSA_2048: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDC3ZXI5cKIPIP9Jm4zqTb06+nta2jBPnxMuu89nw70QLK1y/cn4OlPFtjRwF+oevg+G+AzY2VxGeZ0l6pd4eplfeKc0bibM6aGBBIurBEDSgTzrNZRjnoqbnafosMNNfrTCxWN6g/88cEr0cDQdOViXI064mDtxqbFOTeo1BINvDyauGu5rRJbYCC0RW6UaMrLOyZxVcX3Ri5AiLA3Hx4oIrhPaQaaERQprwNQrJ6qVTjDV2Gy/BTua/F3F377Pvp7U/qQpaENpESZNmabxMED7raM4ED8ECl66ljEg6XexJiuCt6A5udhr59+7wk80tWP8eNFOiGdJAjsCY92jkn1";
+
+    #[test]
+    fn normalizes_recipients() {
+        assert_eq!(
+            normalize_recipient(&format!("  {SSH_ED25519} wurzel@pfropf\n")),
+            SSH_ED25519
+        );
+        assert_eq!(normalize_recipient(&AGE.to_uppercase()), AGE);
+        assert_eq!(
+            normalize_recipient(" age1unencrypted1k5fr0r "),
+            "age1unencrypted1k5fr0r"
+        );
+    }
+
+    #[test]
+    fn determines_rsa_key_size() {
+        let base_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let rules = fs::read_to_string(base_dir.join("example/secrets.nix")).unwrap();
+        let ssh_rsa_3072 = rules
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("sshRsa = \""))
+            .and_then(|line| line.strip_suffix("\";"))
+            .unwrap();
+        assert_eq!(ssh_rsa_bits(ssh_rsa_3072), Some(3072));
+        assert_eq!(ssh_rsa_bits(SSH_RSA_2048), Some(2048));
+        assert_eq!(ssh_rsa_bits(SSH_ED25519), None);
+    }
+
+    #[test]
+    fn reads_authorized_keys() -> Result<()> {
+        let tmpfile = NamedTempFile::new()?;
+        write!(
+            tmpfile.as_file(),
+            "# comment\n\n\
+             {SSH_ED25519} wurzel@pfropf\n\
+             no-pty,from=\"10.0.0.1\" {SSH_ED25519}\n\
+             ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTY= unsupported\n\
+             {AGE}\n"
+        )?;
+
+        let keys = read_public_keys(tmpfile.path())?;
+        let keys = keys
+            .iter()
+            .map(|k| (k.recipient.as_str(), k.comment.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            [
+                (SSH_ED25519, Some("wurzel@pfropf")),
+                (SSH_ED25519, None),
+                (AGE, None)
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,363 @@
+//! Security checks of recipients, identity files and secrets
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    os::unix::prelude::PermissionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use color_eyre::{
+    eyre::{eyre, Result},
+    Help,
+};
+
+use crate::keys;
+
+const MIN_RSA_BITS: usize = 3072;
+const NIX_STORE: &str = "/nix/store";
+
+/// How severe a finding is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Warning => "warning",
+            Self::Error => "error",
+        };
+        f.write_str(s)
+    }
+}
+
+/// A security check, identified by a stable name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Check {
+    /// ssh-rsa recipient with a modulus of less than 3072 bits
+    WeakRsaKey,
+    /// The same recipient written in different forms
+    DuplicateRecipient,
+    /// Identity file readable by group or others
+    IdentityPermissions,
+    /// Identity file or rules file in the world-readable Nix store
+    NixStore,
+    /// Secret writable by others
+    SecretPermissions,
+}
+
+impl Check {
+    pub(crate) const VARIANTS: [&'static str; 5] = [
+        "weak-rsa-key",
+        "duplicate-recipient",
+        "identity-permissions",
+        "nix-store",
+        "secret-permissions",
+    ];
+
+    pub(crate) fn severity(self) -> Severity {
+        match self {
+            Self::WeakRsaKey | Self::DuplicateRecipient => Severity::Warning,
+            Self::IdentityPermissions | Self::NixStore | Self::SecretPermissions => Severity::Error,
+        }
+    }
+}
+
+impl FromStr for Check {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "weak-rsa-key" => Ok(Self::WeakRsaKey),
+            "duplicate-recipient" => Ok(Self::DuplicateRecipient),
+            "identity-permissions" => Ok(Self::IdentityPermissions),
+            "nix-store" => Ok(Self::NixStore),
+            "secret-permissions" => Ok(Self::SecretPermissions),
+            _ => Err(eyre!("Unknown lint check: {s}"))
+                .with_suggestion(|| format!("Use one of {}", Self::VARIANTS.join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::WeakRsaKey => "weak-rsa-key",
+            Self::DuplicateRecipient => "duplicate-recipient",
+            Self::IdentityPermissions => "identity-permissions",
+            Self::NixStore => "nix-store",
+            Self::SecretPermissions => "secret-permissions",
+        };
+        f.write_str(s)
+    }
+}
+
+/// A problem found by a check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Finding {
+    pub check: Check,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Finding {
+    fn new(check: Check, message: String) -> Self {
+        Self {
+            check,
+            severity: check.severity(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}]: {}", self.severity, self.check, self.message)
+    }
+}
+
+/// Abbreviate the long parts of a public key for messages, keeping its type and comment
+fn abbreviate(recipient: &str) -> String {
+    recipient
+        .split_whitespace()
+        .map(|part| {
+            let chars = part.chars().collect::<Vec<_>>();
+            if chars.len() > 24 {
+                let start = chars[..8].iter().collect::<String>();
+                let end = chars[chars.len() - 8..].iter().collect::<String>();
+                format!("{start}...{end}")
+            } else {
+                part.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Check the recipients of all rules for weak and duplicate keys.
+///
+/// A recipient is a duplicate if it is written in different forms, e.g., an SSH key with and
+/// without comment or an ssh-ed25519 key and the native age recipient converted from it.
+pub(crate) fn check_recipients<'a>(recipients: impl IntoIterator<Item = &'a str>) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut weak = Vec::new();
+    let mut spellings: Vec<(String, Vec<&str>)> = Vec::new();
+    for recipient in recipients {
+        let normalized = keys::normalize_recipient(recipient);
+        if let Some(bits) = keys::ssh_rsa_bits(recipient) {
+            if bits < MIN_RSA_BITS && !weak.contains(&normalized) {
+                findings.push(Finding::new(
+                    Check::WeakRsaKey,
+                    format!(
+                        "{} has {bits} bits, use at least {MIN_RSA_BITS}",
+                        abbreviate(recipient)
+                    ),
+                ));
+                weak.push(normalized.clone());
+            }
+        }
+
+        let canonical = keys::ssh_to_age_recipient(&normalized)
+            .map_or(normalized, |converted| converted.to_string());
+        match spellings.iter_mut().find(|(c, _)| *c == canonical) {
+            Some((_, forms)) if !forms.contains(&recipient) => forms.push(recipient),
+            Some(_) => {}
+            None => spellings.push((canonical, vec![recipient])),
+        }
+    }
+
+    for (_, forms) in spellings.into_iter().filter(|(_, forms)| forms.len() > 1) {
+        let forms = forms
+            .iter()
+            .map(|form| format!("'{}'", abbreviate(form)))
+            .collect::<Vec<_>>();
+        findings.push(Finding::new(
+            Check::DuplicateRecipient,
+            format!("the same key is written as {}", forms.join(" and ")),
+        ));
+    }
+    findings
+}
+
+fn is_in_nix_store(path: &Path) -> bool {
+    fs::canonicalize(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .starts_with(NIX_STORE)
+}
+
+/// Check that identity files are neither readable by others nor in the Nix store
+pub(crate) fn check_identities(identity_paths: &[PathBuf]) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    for path in identity_paths {
+        if is_in_nix_store(path) {
+            findings.push(Finding::new(
+                Check::NixStore,
+                format!(
+                    "identity {} is in the world-readable Nix store",
+                    path.display()
+                ),
+            ));
+        } else if let Ok(metadata) = fs::metadata(path) {
+            let mode = metadata.permissions().mode();
+            if mode & 0o077 != 0 {
+                findings.push(Finding::new(
+                    Check::IdentityPermissions,
+                    format!(
+                        "identity {} is accessible by group or others (mode {:o})",
+                        path.display(),
+                        mode & 0o777
+                    ),
+                ));
+            }
+        }
+    }
+    findings
+}
+
+/// Check that the rules file is not in the Nix store
+pub(crate) fn check_rules_file(rules_path: &Path) -> Vec<Finding> {
+    if is_in_nix_store(rules_path) {
+        vec![Finding::new(
+            Check::NixStore,
+            format!(
+                "rules {} are in the Nix store, edits would not be written back",
+                rules_path.display()
+            ),
+        )]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Check that secrets are not writable by others. Group-writable secrets are fine in shared
+/// repositories.
+pub(crate) fn check_secrets(secret_paths: &[PathBuf]) -> Vec<Finding> {
+    secret_paths
+        .iter()
+        .filter_map(|path| {
+            let mode = fs::metadata(path).ok()?.permissions().mode();
+            (mode & 0o002 != 0).then(|| {
+                Finding::new(
+                    Check::SecretPermissions,
+                    format!(
+                        "secret {} is writable by others (mode {:o})",
+                        path.display(),
+                        mode & 0o777
+                    ),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Apply the severities of `--allow` and `--deny` to the findings.
+///
+/// Allowed checks are dropped, denied checks are raised to errors.
+pub(crate) fn apply_levels(
+    findings: Vec<Finding>,
+    allow: &[Check],
+    deny: &[Check],
+) -> Vec<Finding> {
+    let levels = allow
+        .iter()
+        .map(|check| (*check, None))
+        .chain(deny.iter().map(|check| (*check, Some(Severity::Error))))
+        .collect::<HashMap<_, _>>();
+    findings
+        .into_iter()
+        .filter_map(|finding| match levels.get(&finding.check) {
+            Some(None) => None,
+            Some(Some(severity)) => Some(Finding {
+                severity: *severity,
+                ..finding
+            }),
+            None => Some(finding),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test_lint {
+    use std::os::unix::fs::PermissionsExt;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    const SSH_ED25519: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILoPdkEfhcsmW6Lg86GMrEJZnYfFBb7fL9G/IXK7pDQd";
+    const SSH_ED25519_AGE: &str = "age1hufdzlad7ntsley9q99n3dw5x492kg2j62nf7zt7jqa7yt3fsgtqdyeg30";
+    const SSH_RSA_2048: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDC3ZXI5cKIPIP9Jm4zqTb06+nta2jBPnxMuu89nw70QLK1y/cn4OlPFtjRwF+oevg+G+AzY2VxGeZ0l6pd4eplfeKc0bibM6aGBBIurBEDSgTzrNZRjnoqbnafosMNNfrTCxWN6g/88cEr0cDQdOViXI064mDtxqbFOTeo1BINvDyauGu5rRJbYCC0RW6UaMrLOyZxVcX3Ri5AiLA3Hx4oIrhPaQaaERQprwNQrJ6qVTjDV2Gy/BTua/F3F377Pvp7U/qQpaENpESZNmabxMED7raM4ED8ECl66ljEg6XexJiuCt6A5udhr59+7wk80tWP8eNFOiGdJAjsCY92jkn1";
+
+    fn checks(findings: &[Finding]) -> Vec<Check> {
+        findings.iter().map(|finding| finding.check).collect()
+    }
+
+    #[test]
+    fn finds_weak_and_duplicate_recipients() {
+        let with_comment = format!("{SSH_ED25519} wurzel@pfropf");
+        let findings = check_recipients([
+            SSH_ED25519,
+            SSH_ED25519,
+            SSH_RSA_2048,
+            SSH_RSA_2048,
+            &with_comment,
+            SSH_ED25519_AGE,
+        ]);
+        assert_eq!(
+            checks(&findings),
+            [Check::WeakRsaKey, Check::DuplicateRecipient]
+        );
+        assert!(findings[0]
+            .message
+            .ends_with("has 2048 bits, use at least 3072"));
+        assert!(findings[1].message.contains(&abbreviate(SSH_ED25519_AGE)));
+
+        assert_eq!(check_recipients([SSH_ED25519, SSH_ED25519]), []);
+    }
+
+    #[test]
+    fn finds_accessible_files() -> Result<()> {
+        let identity = NamedTempFile::new()?;
+        let secret = NamedTempFile::new()?;
+        let paths = [identity.path().to_path_buf()];
+        let secrets = [secret.path().to_path_buf()];
+
+        fs::set_permissions(identity.path(), fs::Permissions::from_mode(0o600))?;
+        fs::set_permissions(secret.path(), fs::Permissions::from_mode(0o664))?;
+        assert_eq!(check_identities(&paths), []);
+        assert_eq!(check_secrets(&secrets), []);
+
+        fs::set_permissions(identity.path(), fs::Permissions::from_mode(0o640))?;
+        fs::set_permissions(secret.path(), fs::Permissions::from_mode(0o646))?;
+        assert_eq!(
+            checks(&check_identities(&paths)),
+            [Check::IdentityPermissions]
+        );
+        assert_eq!(checks(&check_secrets(&secrets)), [Check::SecretPermissions]);
+
+        assert_eq!(
+            checks(&check_rules_file(Path::new("/nix/store/abc-secrets.nix"))),
+            [Check::NixStore]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn applies_levels() {
+        let findings = check_recipients([SSH_RSA_2048, SSH_ED25519, SSH_ED25519_AGE]);
+        assert!(findings.iter().all(|f| f.severity == Severity::Warning));
+
+        let findings = apply_levels(findings, &[Check::DuplicateRecipient], &[Check::WeakRsaKey]);
+        assert_eq!(checks(&findings), [Check::WeakRsaKey]);
+        assert_eq!(findings[0].severity, Severity::Error);
+        assert_eq!(
+            findings[0].to_string(),
+            "error[weak-rsa-key]: ssh-rsa AAAAB3Nz...CY92jkn1 has 2048 bits, use at least 3072"
+        );
+    }
+}
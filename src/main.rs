@@ -1,27 +1,180 @@
-use color_eyre::eyre::{eyre, Result};
-use std::{env, fs, path::Path, process};
+use color_eyre::{
+    eyre::{eyre, Result},
+    Help,
+};
+use std::{
+    env, fs,
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process,
+};
 
+mod access;
 mod age;
+mod cache;
 mod cli;
+mod config;
+mod header;
+mod keys;
+mod lint;
+mod nix;
+mod policy;
 mod ragenix;
+mod rules_edit;
+mod sidecar;
 mod util;
 
+use ragenix::RulesSource;
+
+#[allow(clippy::too_many_lines)]
 fn main() -> Result<()> {
     color_eyre::install()?;
-    let opts = cli::parse_args(env::args());
+
+    // Precedence: command line > environment > project config > user config
+    let mut user_config = match config::Config::user_path() {
+        Some(path) => config::Config::read(&path)?.unwrap_or_default(),
+        None => config::Config::default(),
+    };
+    user_config.discover_rules()?;
+    let rules_name = user_config.rules_name().to_string();
+    let mut opts = cli::parse_args_with_defaults(env::args(), &user_config.defaults());
+    let sources = ragenix::resolve_rules(
+        &opts.rules,
+        &rules_name,
+        opts.rules_base_dir.as_deref().map(Path::new),
+    )?;
+    if let [RulesSource::File(rules_path)] = &sources[..] {
+        if let Some(mut project_config) = config::Config::read_project(rules_path)? {
+            if opts.restrict_eval {
+                project_config = project_config.restricted();
+            }
+            let defaults = project_config.or(user_config).defaults();
+            opts = cli::parse_args_with_defaults(env::args(), &defaults);
+        }
+    }
+    let nix_settings = nix::Settings {
+        args: opts.args.clone(),
+        argstrs: opts.argstrs.clone(),
+        impure: opts.impure,
+        options: opts.nix_options.clone(),
+        nix: opts.nix.as_deref().map(PathBuf::from),
+        restrict_eval: opts.restrict_eval,
+        cache: opts.cache,
+    };
+    let nix_args = nix_settings.to_args();
+    nix_settings.install()?;
+    if opts.verbose && !(opts.schema || opts.keygen.is_some() || opts.convert.is_some()) {
+        for source in &sources {
+            match source {
+                RulesSource::File(path) => println!("Using rules file {}", path.display()),
+                RulesSource::Flake { reference, .. } => println!("Using rules {reference}"),
+                RulesSource::Stdin => println!("Using rules from stdin"),
+            }
+        }
+        if sources.iter().any(RulesSource::needs_nix) {
+            println!(
+                "Using evaluator {}",
+                nix::Evaluator::new(opts.nix.as_deref().map(Path::new)).describe()
+            );
+        }
+        if !nix_args.is_empty() {
+            println!(
+                "Using nix arguments {}",
+                shlex::try_join(nix_args.iter().map(String::as_str))?
+            );
+        }
+    }
 
     if opts.schema {
-        print!("{}", ragenix::AGENIX_JSON_SCHEMA_STRING);
-    } else {
-        if let Err(report) = ragenix::validate_rules_file(&opts.rules) {
+        print!("{}", *ragenix::AGENIX_JSON_SCHEMA_STRING);
+    } else if let Some(path) = &opts.keygen {
+        ragenix::keygen(Path::new(path), opts.passphrase, &mut std::io::stdout())?;
+    } else if let Some(input) = &opts.convert {
+        ragenix::convert(input, &mut std::io::stdout())?;
+    } else if let Some(pubkey_paths) = &opts.init {
+        let rules_path = single_rules_file(&sources, "--init")?;
+        ragenix::init(rules_path, pubkey_paths, &mut std::io::stdout())?;
+        if let Err(report) = ragenix::validate_rules(&RulesSource::File(rules_path.to_path_buf())) {
             eprintln!(
-                "error: secrets rules are invalid: '{}'\n{report}",
-                &opts.rules
+                "error: generated secrets rules are invalid: '{}'\n{report}",
+                rules_path.display()
             );
             process::exit(1);
         }
-
-        let rules = ragenix::parse_rules(&opts.rules)?;
+    } else if let Some(paths) = &opts.inspect {
+        ragenix::inspect(&sources, paths, &mut std::io::stdout())?;
+    } else if let Some(path) = &opts.add_rule {
+        ragenix::add_rule(
+            single_rules_file(&sources, "--add-rule")?,
+            Path::new(path),
+            &opts.recipients,
+            &mut std::io::stdout(),
+        )?;
+    } else if let Some(recipient) = &opts.add_recipient {
+        ragenix::add_recipient(
+            single_rules_file(&sources, "--add-recipient")?,
+            recipient,
+            &opts.secrets,
+            &mut std::io::stdout(),
+        )?;
+    } else if let Some(recipient) = &opts.revoke {
+        // `EDITOR`/`--editor` is mandatory if action is `--revoke`
+        let editor = &opts.editor.unwrap();
+        let rules_path = single_rules_file(&sources, "--revoke")?;
+        let policy = ragenix::load_policy(&sources, opts.policy.as_deref())?;
+        ragenix::revoke(
+            rules_path,
+            recipient,
+            &opts.secrets,
+            &opts.identities.unwrap_or_default(),
+            editor,
+            opts.temp_dir.as_deref().map(Path::new),
+            &policy,
+            &mut std::io::stdout(),
+        )?;
+    } else if let Some(recipient) = &opts.remove_recipient {
+        ragenix::remove_recipient(
+            single_rules_file(&sources, "--remove-recipient")?,
+            recipient,
+            &opts.secrets,
+            &mut std::io::stdout(),
+        )?;
+    } else {
+        if opts.edit.is_some()
+            && opts.editor.as_deref().is_some_and(util::is_stdin)
+            && sources.contains(&RulesSource::Stdin)
+        {
+            return Err(eyre!(
+                "The rules and the secret can't both be read from stdin"
+            ))
+            .with_suggestion(|| "Pass a rules file with --rules");
+        }
+        let mut rule_sets = Vec::new();
+        for source in &sources {
+            let instance = match ragenix::eval_rules(source) {
+                Ok(instance) => instance,
+                Err(report) => {
+                    // Violations of a restricted evaluation are invalid rules, other errors aren't
+                    let violations = report.downcast::<nix::Violations>()?;
+                    eprintln!("error: secrets rules are invalid: '{source}'\n{violations}");
+                    process::exit(1);
+                }
+            };
+            if let Err(report) = ragenix::check_rules(
+                source,
+                &instance,
+                ragenix::Validation {
+                    strict: opts.strict,
+                    // Only encrypting needs the plugins
+                    plugins: opts.edit.is_some() || opts.rekey,
+                },
+            ) {
+                eprintln!("error: secrets rules are invalid: '{source}'\n{report}");
+                process::exit(1);
+            }
+            rule_sets.push((source.clone(), ragenix::rules_from_json(source, &instance)?));
+        }
+        let mut rules = ragenix::merge_rules(rule_sets)?;
         if opts.verbose {
             println!("{rules:#?}");
         }
@@ -33,6 +186,24 @@ fn main() -> Result<()> {
             let edit_path = std::env::current_dir()
                 .and_then(fs::canonicalize)
                 .map(|p| p.join(path_normalized))?;
+            if !rules.iter().any(|x| x.path == edit_path) {
+                if opts.recipients.is_empty() {
+                    return Err(eyre!("No rule for the given file {}", path)).with_suggestion(
+                        || "Pass the recipients of the new secret with --recipient to add a rule",
+                    );
+                }
+                if !(opts.yes || confirm(&format!("No rule for {path}, add one?"))?) {
+                    process::exit(1);
+                }
+                let rules_path = single_rules_file(&sources, "Adding a rule")?;
+                ragenix::add_rule(
+                    rules_path,
+                    Path::new(path),
+                    &opts.recipients,
+                    &mut std::io::stdout(),
+                )?;
+                rules = ragenix::parse_rules(&RulesSource::File(rules_path.to_path_buf()))?;
+            }
             let rule = rules
                 .into_iter()
                 .find(|x| x.path == edit_path)
@@ -40,11 +211,79 @@ fn main() -> Result<()> {
 
             // `EDITOR`/`--editor` is mandatory if action is `--edit`
             let editor = &opts.editor.unwrap();
-            ragenix::edit(&rule, &identities, editor, &mut std::io::stdout())?;
+            let policy = ragenix::load_policy(&sources, opts.policy.as_deref())?;
+            ragenix::edit(
+                &rule,
+                &identities,
+                editor,
+                opts.temp_dir.as_deref().map(Path::new),
+                &policy,
+                &mut std::io::stdout(),
+            )?;
+        } else if let Some(key) = &opts.readable_by {
+            ragenix::readable_by(&sources, &rules, key, &mut std::io::stdout())?;
+        } else if opts.accessible {
+            ragenix::accessible(&sources, &rules, &identities, &mut std::io::stdout())?;
+        } else if let Some(format) = &opts.access_matrix {
+            ragenix::access_matrix(&sources, &rules, format.parse()?, &mut std::io::stdout())?;
         } else if opts.rekey {
-            ragenix::rekey(&rules, &identities, &mut std::io::stdout())?;
+            let policy = ragenix::load_policy(&sources, opts.policy.as_deref())?;
+            ragenix::rekey(&rules, &identities, &policy, &mut std::io::stdout())?;
+        } else if opts.check_sidecars || opts.check_headers || opts.lint {
+            let ok = if opts.check_sidecars {
+                ragenix::check_sidecars(&rules, &identities, &mut std::io::stdout())?
+            } else if opts.check_headers {
+                ragenix::check_headers(&sources, &rules, &mut std::io::stdout())?
+            } else {
+                let allow = opts
+                    .allow
+                    .iter()
+                    .map(|check| check.parse())
+                    .collect::<Result<Vec<_>>>()?;
+                let deny = opts
+                    .deny
+                    .iter()
+                    .map(|check| check.parse())
+                    .collect::<Result<Vec<_>>>()?;
+                ragenix::lint(
+                    &sources,
+                    &rules,
+                    &identities,
+                    &allow,
+                    &deny,
+                    &mut std::io::stdout(),
+                )?
+            };
+            if !ok {
+                process::exit(1);
+            }
         }
     }
 
     Ok(())
 }
+
+/// The rules file of actions which support a single one only
+fn single_rules_file<'a>(sources: &'a [RulesSource], action: &str) -> Result<&'a Path> {
+    match sources {
+        [source] => source.nix_file().ok_or_else(|| {
+            eyre!("{action} can't modify the rules {source}")
+                .with_suggestion(|| "Pass a Nix rules file with --rules or edit the rules instead")
+        }),
+        _ => Err(eyre!("{action} supports a single rules file only"))
+            .with_suggestion(|| "Pass a single rules file with --rules"),
+    }
+}
+
+/// Ask the user for confirmation if stdin is a terminal, refuse otherwise
+fn confirm(question: &str) -> Result<bool> {
+    if !io::stdin().is_terminal() {
+        eprintln!("{question} Refusing as stdin is not a terminal, pass --yes to agree");
+        return Ok(false);
+    }
+    eprint!("{question} [y/N] ");
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
@@ -0,0 +1,586 @@
+//! Evaluation of Nix expressions with the `nix` binary
+
+use std::{
+    error, fmt, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::OnceLock,
+};
+
+use color_eyre::{
+    eyre::{eyre, Result, WrapErr},
+    Help, SectionExt,
+};
+
+use crate::util;
+
+/// Settings forwarded to all evaluations of rules and policies
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Settings {
+    /// Arguments passed to a rules file which is a function, as Nix expressions
+    pub args: Vec<(String, String)>,
+    /// Arguments passed to a rules file which is a function, as strings
+    pub argstrs: Vec<(String, String)>,
+    /// Allow access to the environment, e.g., `builtins.getEnv` and `NIX_PATH`
+    pub impure: bool,
+    /// Nix configuration settings like `allow-import-from-derivation`
+    pub options: Vec<(String, String)>,
+    /// The `nix` or `nix-instantiate` binary; see [`Evaluator::new`] for the default
+    pub nix: Option<PathBuf>,
+    /// Evaluate untrusted rules in restricted and pure mode without import from derivation,
+    /// allowing access to the repository of the rules file only
+    pub restrict_eval: bool,
+    /// Cache the evaluations of rules files in the user cache, see [`crate::cache`]
+    pub cache: bool,
+}
+
+/// Nix settings of a restricted evaluation, which take precedence over `--option`
+const RESTRICTED_OPTIONS: [(&str, &str); 3] = [
+    ("restrict-eval", "true"),
+    ("pure-eval", "true"),
+    ("allow-import-from-derivation", "false"),
+];
+
+/// Accesses forbidden by a restricted evaluation, reported by Nix
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Violations(Vec<String>);
+
+impl Violations {
+    /// The violations Nix reports in `stderr` of a failed evaluation, if any
+    fn from_stderr(stderr: &str) -> Option<Self> {
+        let violations = stderr
+            .lines()
+            .map(str::trim)
+            // Nix says "pure eval mode" or "pure evaluation mode" depending on its version
+            .filter(|line| {
+                line.contains("in restricted mode")
+                    || line.contains("in pure eval")
+                    || line.contains("allow-import-from-derivation")
+            })
+            .map(|line| line.strip_prefix("error: ").unwrap_or(line).to_string())
+            .collect::<Vec<_>>();
+        (!violations.is_empty()).then_some(Self(violations))
+    }
+}
+
+impl fmt::Display for Violations {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines = self
+            .0
+            .iter()
+            .map(|violation| format!(" - {violation}"))
+            .collect::<Vec<_>>();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl error::Error for Violations {}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+impl Settings {
+    /// Use these settings for all evaluations of this process
+    pub(crate) fn install(self) -> Result<()> {
+        SETTINGS
+            .set(self)
+            .map_err(|_| eyre!("Nix settings are already installed"))
+    }
+
+    /// The installed settings or the defaults if none were installed
+    pub(crate) fn current() -> &'static Self {
+        SETTINGS.get_or_init(Self::default)
+    }
+
+    /// The arguments of `nix eval` applying these settings
+    pub(crate) fn to_args(&self) -> Vec<String> {
+        let mut args = self.pair_args();
+        if self.impure && !self.restrict_eval {
+            args.push("--impure".to_string());
+        }
+        args
+    }
+
+    /// The arguments `--arg`, `--argstr` and `--option` supported by all evaluators
+    fn pair_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for (flag, pairs) in [
+            ("--arg", &self.args),
+            ("--argstr", &self.argstrs),
+            ("--option", &self.options),
+        ] {
+            for (name, value) in pairs {
+                args.extend([flag.to_string(), name.clone(), value.clone()]);
+            }
+        }
+        if self.restrict_eval {
+            for (name, value) in RESTRICTED_OPTIONS {
+                args.extend(["--option", name, value].map(String::from));
+            }
+        }
+        args
+    }
+
+    /// The arguments allowing a restricted evaluation of the rules file at `path` to access the
+    /// repository containing it
+    fn include_args(&self, path: &Path) -> Vec<String> {
+        if !self.restrict_eval {
+            return Vec::new();
+        }
+        let dir = fs::canonicalize(path)
+            .ok()
+            .and_then(|path| path.parent().map(Path::to_path_buf))
+            .unwrap_or_else(|| PathBuf::from("."));
+        let root = util::repository_root(&dir);
+        vec!["-I".to_string(), root.to_string_lossy().to_string()]
+    }
+}
+
+/// What to evaluate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Target<'a> {
+    /// A Nix file
+    File(&'a Path),
+    /// A flake output attribute like `.#agenix.rules`
+    Flake(&'a str),
+    /// A Nix expression inspecting the Nix file `file`
+    Expr { expr: &'a str, file: &'a Path },
+}
+
+/// Quote a string as a Nix string literal
+pub(crate) fn quote(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${");
+    format!("\"{escaped}\"")
+}
+
+/// The location of a definition as reported by `builtins.unsafeGetAttrPos`
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub(crate) struct Position {
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    /// The line of the definition with a marker below its column, like Nix shows it in errors
+    pub(crate) fn snippet(&self) -> Option<String> {
+        let source = fs::read_to_string(&self.file).ok()?;
+        let line = source.lines().nth(self.line.checked_sub(1)?)?;
+        let number = self.line.to_string();
+        Some(format!(
+            "{number}| {line}\n{}| {}^",
+            " ".repeat(number.len()),
+            " ".repeat(self.column.saturating_sub(1))
+        ))
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file.display(), self.line, self.column)
+    }
+}
+
+/// The positions of the definitions of the attributes at `attr_paths` in the rules file at
+/// `path`, evaluated with the installed settings.
+///
+/// An attribute without a position, e.g., one created by `builtins.listToAttrs`, has none.
+pub(crate) fn attr_positions(
+    path: &Path,
+    attr_paths: &[Vec<String>],
+) -> Result<Vec<Option<Position>>> {
+    let canonical_path = fs::canonicalize(path)?;
+    let positions = attr_paths
+        .iter()
+        .map(|attr_path| match attr_path.split_last() {
+            Some((name, parents)) => {
+                let parent = parents.iter().map(|parent| format!(".{}", quote(parent)));
+                format!(
+                    "(builtins.unsafeGetAttrPos {} rules{})",
+                    quote(name),
+                    parent.collect::<String>()
+                )
+            }
+            None => "null".to_string(),
+        })
+        .collect::<Vec<_>>();
+    // Rules which are a function receive the arguments of the evaluation, as `--arg` only
+    // applies to the outermost function
+    let expr = format!(
+        "{{ ... }}@args: let f = import (/. + {}); rules = if builtins.isFunction f then f args else f; in [ {} ]",
+        quote(&canonical_path.to_string_lossy()),
+        positions.join(" ")
+    );
+    let val = eval_json(Target::Expr {
+        expr: &expr,
+        file: &canonical_path,
+    })?;
+    Ok(serde_json::from_value(val)?)
+}
+
+/// The program evaluating Nix expressions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Evaluator {
+    /// `nix eval` of the `nix-command` experimental feature
+    Nix(PathBuf),
+    /// `nix-instantiate --eval` for Nix versions without the `nix-command` feature
+    NixInstantiate(PathBuf),
+}
+
+impl Evaluator {
+    /// The evaluator at `path`, which is `nix-instantiate` if the file is called so.
+    ///
+    /// Without a path, `nix` as given at build time is used if it exists, or else `nix` or
+    /// `nix-instantiate` found in `PATH`.
+    pub(crate) fn new(path: Option<&Path>) -> Self {
+        let path = path.map_or_else(Self::default_path, Path::to_path_buf);
+        if path
+            .file_name()
+            .is_some_and(|name| name == "nix-instantiate")
+        {
+            Self::NixInstantiate(path)
+        } else {
+            Self::Nix(path)
+        }
+    }
+
+    fn default_path() -> PathBuf {
+        let built_in = Path::new(env!("RAGENIX_NIX_BIN_PATH"));
+        if built_in.is_absolute() && built_in.is_file() {
+            return built_in.to_path_buf();
+        }
+        ["nix", "nix-instantiate"]
+            .iter()
+            .find_map(|name| util::find_executable(name))
+            .unwrap_or_else(|| PathBuf::from("nix"))
+    }
+
+    /// The evaluator of the installed [`Settings`]
+    pub(crate) fn current() -> Self {
+        Self::new(Settings::current().nix.as_deref())
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        match self {
+            Self::Nix(path) | Self::NixInstantiate(path) => path,
+        }
+    }
+
+    /// The version the evaluator reports, e.g., `nix (Nix) 2.18.1`
+    pub(crate) fn version(&self) -> Option<String> {
+        let output = process::Command::new(self.path())
+            .arg("--version")
+            .output()
+            .ok()?;
+        output.status.success().then(|| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        })
+    }
+
+    /// The evaluator and its version for diagnostics
+    pub(crate) fn describe(&self) -> String {
+        let version = self.version();
+        format!(
+            "{} ({})",
+            self.path().display(),
+            version.as_deref().unwrap_or("unknown version")
+        )
+    }
+
+    /// The command evaluating `target` to JSON with `settings`, which logs the evaluated files if
+    /// `log_files` is set
+    fn command(
+        &self,
+        settings: &Settings,
+        target: Target,
+        log_files: bool,
+    ) -> Result<process::Command> {
+        let mut command = process::Command::new(self.path());
+        match self {
+            Self::Nix(_) => {
+                command
+                    .arg("--extra-experimental-features")
+                    .arg("nix-command flakes")
+                    .arg("eval")
+                    .arg("--json")
+                    .args(log_files.then_some("--debug"))
+                    .args(settings.to_args());
+                match target {
+                    Target::File(path) => command
+                        .args(settings.include_args(path))
+                        .arg("--no-net")
+                        .arg("--file")
+                        .arg(path),
+                    Target::Flake(reference) => command.arg(reference),
+                    Target::Expr { expr, file } => command
+                        .args(settings.include_args(file))
+                        .arg("--no-net")
+                        .arg("--expr")
+                        .arg(expr),
+                };
+            }
+            Self::NixInstantiate(_) => {
+                // `nix-instantiate` is impure anyway
+                command
+                    .arg("--eval")
+                    .arg("--strict")
+                    .arg("--json")
+                    .args(log_files.then_some("--debug"))
+                    .args(settings.pair_args());
+                match target {
+                    Target::File(path) => command.args(settings.include_args(path)).arg(path),
+                    Target::Flake(_) => {
+                        return Err(eyre!("nix-instantiate can't evaluate flake outputs"))
+                            .with_suggestion(|| "Choose nix as evaluator with --nix");
+                    }
+                    Target::Expr { expr, file } => command
+                        .args(settings.include_args(file))
+                        .arg("--expr")
+                        .arg(expr),
+                };
+            }
+        }
+        Ok(command)
+    }
+}
+
+/// Evaluate `target` to JSON and parse the output into a serde JSON value.
+///
+/// A restricted evaluation failing due to forbidden accesses returns [`Violations`].
+pub(crate) fn eval_json(target: Target) -> Result<serde_json::Value> {
+    evaluate(target, false).map(|(val, _)| val)
+}
+
+/// Like [`eval_json`], but also returns the files outside of the Nix store which Nix reports to
+/// have evaluated, i.e., imported. Files read otherwise, e.g., by `builtins.readFile`, are not
+/// reported.
+pub(crate) fn eval_json_with_files(target: Target) -> Result<(serde_json::Value, Vec<PathBuf>)> {
+    evaluate(target, true)
+}
+
+fn evaluate(target: Target, log_files: bool) -> Result<(serde_json::Value, Vec<PathBuf>)> {
+    let settings = Settings::current();
+    let evaluator = Evaluator::new(settings.nix.as_deref());
+    let name = match target {
+        Target::File(path) | Target::Expr { file: path, .. } => path.to_string_lossy(),
+        Target::Flake(reference) => reference.into(),
+    };
+    let output = evaluator
+        .command(settings, target, log_files)?
+        .output()
+        .wrap_err_with(|| {
+            format!(
+                "Failed to execute the Nix evaluator {}",
+                evaluator.path().display()
+            )
+        })
+        .with_suggestion(|| "Install Nix or choose the evaluator with --nix")?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        if settings.restrict_eval {
+            if let Some(violations) = Violations::from_stderr(&stderr) {
+                return Err(violations.into());
+            }
+        }
+        // Skip the debug messages preceding the error
+        let error = stderr
+            .find("error:")
+            .map_or(&stderr[..], |start| &stderr[start..]);
+        return Err(eyre!("Failed to read {} as JSON", name))
+            .with_section(|| evaluator.describe().header("Evaluator:"))
+            .with_section(|| error.trim().to_string().header("Stderr:"));
+    }
+
+    let val = serde_json::from_slice(&output.stdout)?;
+    let files = stderr
+        .lines()
+        .filter_map(|line| line.strip_prefix("evaluating file '")?.strip_suffix('\''))
+        .map(PathBuf::from)
+        .filter(|path| !path.starts_with("/nix/store") && path.is_file())
+        .collect();
+    Ok((val, files))
+}
+
+#[cfg(test)]
+mod test_settings {
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn builds_nix_arguments() {
+        assert_eq!(Settings::default().to_args(), Vec::<String>::new());
+        let settings = Settings {
+            args: vec![("system".to_string(), "\"x86_64-linux\"".to_string())],
+            argstrs: vec![("host".to_string(), "wurzel".to_string())],
+            impure: true,
+            options: vec![("pure-eval".to_string(), "false".to_string())],
+            nix: None,
+            restrict_eval: false,
+            cache: false,
+        };
+        assert_eq!(
+            settings.to_args(),
+            [
+                "--arg",
+                "system",
+                "\"x86_64-linux\"",
+                "--argstr",
+                "host",
+                "wurzel",
+                "--option",
+                "pure-eval",
+                "false",
+                "--impure"
+            ]
+        );
+
+        // The restricted settings override the options and `--impure`
+        let restricted = Settings {
+            restrict_eval: true,
+            ..settings
+        };
+        assert_eq!(
+            restricted.to_args()[6..],
+            [
+                "--option",
+                "pure-eval",
+                "false",
+                "--option",
+                "restrict-eval",
+                "true",
+                "--option",
+                "pure-eval",
+                "true",
+                "--option",
+                "allow-import-from-derivation",
+                "false"
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_restriction_violations() {
+        let stderr = indoc! {"
+            evaluating file '/home/wurzel/secrets/secrets.nix'
+            error:
+                   … while calling the 'import' builtin
+                     at /home/wurzel/secrets/secrets.nix:1:12:
+                        1| let keys = import /etc/ragenix/keys.nix; in
+                   error: access to absolute path '/etc/ragenix/keys.nix' is forbidden in restricted mode
+        "};
+        assert_eq!(
+            Violations::from_stderr(stderr).map(|violations| violations.to_string()),
+            Some(
+                " - access to absolute path '/etc/ragenix/keys.nix' is forbidden in restricted mode"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            Violations::from_stderr("error: attribute 'wurzel' missing"),
+            None
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_position {
+    use super::*;
+
+    #[test]
+    fn shows_snippet() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("secrets.nix");
+        fs::write(&file, "{\n  \"wurzel.age\".publicKeys = \"pfropf\";\n}\n")?;
+        let position = Position {
+            file: file.clone(),
+            line: 2,
+            column: 16,
+        };
+        assert_eq!(position.to_string(), format!("{}:2:16", file.display()));
+        assert_eq!(
+            position.snippet().as_deref(),
+            Some("2|   \"wurzel.age\".publicKeys = \"pfropf\";\n |                ^")
+        );
+        assert_eq!(
+            Position {
+                line: 4,
+                ..position
+            }
+            .snippet(),
+            None
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_evaluator {
+    use super::*;
+
+    fn args(command: &process::Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn builds_evaluator_commands() -> Result<()> {
+        let settings = Settings {
+            argstrs: vec![("host".to_string(), "wurzel".to_string())],
+            impure: true,
+            ..Settings::default()
+        };
+        let file = Target::File(Path::new("secrets.nix"));
+
+        let nix = Evaluator::new(Some(Path::new("/run/current-system/sw/bin/nix")));
+        assert_eq!(nix, Evaluator::Nix("/run/current-system/sw/bin/nix".into()));
+        assert_eq!(
+            args(&nix.command(&settings, file, false)?),
+            [
+                "--extra-experimental-features",
+                "nix-command flakes",
+                "eval",
+                "--json",
+                "--argstr",
+                "host",
+                "wurzel",
+                "--impure",
+                "--no-net",
+                "--file",
+                "secrets.nix"
+            ]
+        );
+
+        let nix_instantiate = Evaluator::new(Some(Path::new("nix-instantiate")));
+        assert_eq!(
+            nix_instantiate,
+            Evaluator::NixInstantiate("nix-instantiate".into())
+        );
+        assert_eq!(
+            args(&nix_instantiate.command(&settings, file, true)?),
+            [
+                "--eval",
+                "--strict",
+                "--json",
+                "--debug",
+                "--argstr",
+                "host",
+                "wurzel",
+                "secrets.nix"
+            ]
+        );
+        assert!(nix_instantiate
+            .command(&settings, Target::Flake(".#agenix.rules"), false)
+            .is_err());
+        Ok(())
+    }
+}
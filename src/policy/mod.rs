@@ -0,0 +1,376 @@
+//! Constraints on the recipients of secrets which are enforced before any secret is written
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::LazyLock,
+};
+
+use color_eyre::{
+    eyre::{eyre, Result},
+    Help, SectionExt,
+};
+use jsonschema::JSONSchema;
+
+use crate::keys;
+
+pub(crate) static POLICY_JSON_SCHEMA_STRING: &str = std::include_str!("policy.schema.json");
+
+static POLICY_JSON_SCHEMA: LazyLock<serde_json::Value> =
+    LazyLock::new(|| serde_json::from_str(POLICY_JSON_SCHEMA_STRING).expect("Valid schema!"));
+
+/// The type of a recipient
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RecipientType {
+    X25519,
+    SshEd25519,
+    SshRsa,
+    Plugin,
+}
+
+impl RecipientType {
+    pub(crate) const VARIANTS: [&'static str; 4] = ["x25519", "ssh-ed25519", "ssh-rsa", "plugin"];
+
+    /// The type of `recipient` or `None` if it isn't a valid recipient
+    pub(crate) fn of(recipient: &str) -> Option<Self> {
+        if recipient.parse::<age::x25519::Recipient>().is_ok() {
+            Some(Self::X25519)
+        } else if let Ok(pk) = recipient.parse::<age::ssh::Recipient>() {
+            match pk {
+                age::ssh::Recipient::SshEd25519(..) => Some(Self::SshEd25519),
+                age::ssh::Recipient::SshRsa(..) => Some(Self::SshRsa),
+            }
+        } else if recipient.parse::<age::plugin::Recipient>().is_ok() {
+            Some(Self::Plugin)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for RecipientType {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "x25519" => Ok(Self::X25519),
+            "ssh-ed25519" => Ok(Self::SshEd25519),
+            "ssh-rsa" => Ok(Self::SshRsa),
+            "plugin" => Ok(Self::Plugin),
+            _ => Err(eyre!("Unknown recipient type: {s}"))
+                .with_suggestion(|| format!("Use one of {}", Self::VARIANTS.join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for RecipientType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::X25519 => "x25519",
+            Self::SshEd25519 => "ssh-ed25519",
+            Self::SshRsa => "ssh-rsa",
+            Self::Plugin => "plugin",
+        };
+        f.write_str(s)
+    }
+}
+
+/// A constraint on the recipients of the secrets matching its patterns
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Constraint {
+    pub name: String,
+    /// The directory of the rules file the constraint belongs to
+    pub base_dir: PathBuf,
+    /// Patterns of secret paths relative to `base_dir`, all secrets below it if empty
+    pub secrets: Vec<String>,
+    /// Normalized recipients every matching secret must be encrypted to
+    pub require: Vec<String>,
+    pub min_recipients: Option<usize>,
+    /// Normalized recipients counted by `min_recipients`, all recipients if `None`
+    pub from: Option<Vec<String>>,
+    pub forbid_types: Vec<RecipientType>,
+}
+
+/// Match a path against a pattern in which `*` matches any sequence of characters
+fn matches_pattern(pattern: &str, path: &str) -> bool {
+    let Some((prefix, rest)) = pattern.split_once('*') else {
+        return pattern == path;
+    };
+    let Some(path) = path.strip_prefix(prefix) else {
+        return false;
+    };
+    (0..=path.len())
+        .filter(|i| path.is_char_boundary(*i))
+        .any(|i| matches_pattern(rest, &path[i..]))
+}
+
+impl Constraint {
+    /// The path of a secret relative to [`Constraint::base_dir`] if it's located below it
+    fn secret_name(&self, path: &Path) -> Option<String> {
+        path.strip_prefix(&self.base_dir)
+            .ok()
+            .map(|path| path.to_string_lossy().to_string())
+    }
+
+    fn applies_to(&self, path: &Path) -> bool {
+        self.secret_name(path).is_some_and(|secret| {
+            self.secrets.is_empty()
+                || self
+                    .secrets
+                    .iter()
+                    .any(|pattern| matches_pattern(pattern, &secret))
+        })
+    }
+
+    /// Describe how `public_keys` violate the constraint
+    fn violations(&self, public_keys: &[String]) -> Vec<String> {
+        let recipients = public_keys
+            .iter()
+            .map(|pk| keys::normalize_recipient(pk))
+            .collect::<Vec<_>>();
+
+        let mut violations = Vec::new();
+        for required in &self.require {
+            if !recipients.contains(required) {
+                violations.push(format!("not encrypted to required recipient {required}"));
+            }
+        }
+        if let Some(min) = self.min_recipients {
+            let (count, counted) = match &self.from {
+                Some(from) => (
+                    recipients.iter().filter(|r| from.contains(r)).count(),
+                    " of the listed",
+                ),
+                None => (recipients.len(), ""),
+            };
+            if count < min {
+                violations.push(format!(
+                    "encrypted to {count}{counted} recipient(s), at least {min} required"
+                ));
+            }
+        }
+        for recipient in &recipients {
+            if let Some(kind) = RecipientType::of(recipient) {
+                if self.forbid_types.contains(&kind) {
+                    violations.push(format!(
+                        "encrypted to forbidden {kind} recipient {recipient}"
+                    ));
+                }
+            }
+        }
+        violations
+    }
+}
+
+/// Constraints on the recipients of the secrets of one or more rules files
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Policy {
+    pub constraints: Vec<Constraint>,
+}
+
+impl Policy {
+    /// Create a policy from its JSON representation after validating it against
+    /// [`POLICY_JSON_SCHEMA`]
+    pub(crate) fn from_json(base_dir: &Path, instance: &serde_json::Value) -> Result<Self> {
+        let compiled = JSONSchema::options()
+            .with_draft(jsonschema::Draft::Draft7)
+            .compile(&POLICY_JSON_SCHEMA)?;
+        if let Err(errors) = compiled.validate(instance) {
+            let error_msg = errors
+                .into_iter()
+                .map(|err| format!(" - {}: {err}", err.instance_path))
+                .collect::<Vec<String>>()
+                .join("\n");
+            return Err(eyre!("Invalid policy")).with_section(|| error_msg.header("Errors:"));
+        }
+
+        let strings = |value: &serde_json::Value| -> Vec<String> {
+            value
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|item| item.as_str().map(ToString::to_string))
+                .collect()
+        };
+        let recipients = |value: &serde_json::Value| -> Vec<String> {
+            strings(value)
+                .iter()
+                .map(|r| keys::normalize_recipient(r))
+                .collect()
+        };
+
+        // It's fine to force unwrap here as we validated the JSON schema
+        let mut constraints = Vec::new();
+        for (i, constraint) in instance["constraints"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .enumerate()
+        {
+            constraints.push(Constraint {
+                name: constraint["name"]
+                    .as_str()
+                    .map_or_else(|| format!("#{}", i + 1), ToString::to_string),
+                base_dir: base_dir.to_path_buf(),
+                secrets: strings(&constraint["secrets"]),
+                require: recipients(&constraint["require"]),
+                min_recipients: constraint["minRecipients"]
+                    .as_u64()
+                    .map(|min| usize::try_from(min).unwrap_or(usize::MAX)),
+                from: constraint.get("from").map(recipients),
+                forbid_types: strings(&constraint["forbidTypes"])
+                    .iter()
+                    .map(|kind| kind.parse())
+                    .collect::<Result<_>>()?,
+            });
+        }
+
+        Ok(Self { constraints })
+    }
+
+    /// Add the constraints of another policy, e.g., of another rules file
+    pub(crate) fn extend(&mut self, other: Self) {
+        self.constraints.extend(other.constraints);
+    }
+
+    /// Describe how the recipients of the secret at `path` violate the constraints applying to it
+    pub(crate) fn violations(&self, path: &Path, public_keys: &[String]) -> Vec<String> {
+        self.constraints
+            .iter()
+            .filter(|constraint| constraint.applies_to(path))
+            .flat_map(|constraint| {
+                constraint
+                    .violations(public_keys)
+                    .into_iter()
+                    .map(move |violation| format!("{}: {violation}", constraint.name))
+            })
+            .collect()
+    }
+
+    /// Check the recipients of the secret at `path` against all constraints applying to it
+    pub(crate) fn check(&self, path: &Path, public_keys: &[String]) -> Result<()> {
+        let violations = self.violations(path, public_keys);
+        if violations.is_empty() {
+            return Ok(());
+        }
+
+        let error_msg = violations
+            .iter()
+            .map(|violation| format!(" - {violation}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let secret = self
+            .constraints
+            .iter()
+            .find_map(|constraint| constraint.secret_name(path))
+            .unwrap_or_else(|| path.display().to_string());
+        Err(eyre!("The rule for {secret} violates the recipient policy"))
+            .with_section(|| error_msg.header("Constraints:"))
+            .with_suggestion(|| "Change the recipients of the rule or the policy")
+    }
+}
+
+#[cfg(test)]
+mod test_policy {
+    use serde_json::json;
+
+    use super::*;
+
+    const SSH_ED25519: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILoPdkEfhcsmW6Lg86GMrEJZnYfFBb7fL9G/IXK7pDQd";
+    const AGE: &str = "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk";
+    const PLUGIN: &str = "age1unencrypted1k5fr0r";
+
+    fn policy() -> Result<Policy> {
+        Policy::from_json(
+            Path::new("/secrets"),
+            &json!({
+                "constraints": [
+                    { "name": "recovery", "require": [ AGE ] },
+                    { "secrets": [ "prod/*" ], "forbidTypes": [ "plugin" ] },
+                    {
+                        "name": "humans",
+                        "secrets": [ "*.passwd.age" ],
+                        "minRecipients": 2,
+                        "from": [ format!("{SSH_ED25519} wurzel@pfropf"), PLUGIN ]
+                    }
+                ]
+            }),
+        )
+    }
+
+    fn public_keys(recipients: &[&str]) -> Vec<String> {
+        recipients.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn matches_patterns() {
+        assert!(matches_pattern("prod/*", "prod/db.age"));
+        assert!(matches_pattern("*.age", "prod/db.age"));
+        assert!(matches_pattern("*/db*", "prod/db.age"));
+        assert!(!matches_pattern("prod/*", "dev/db.age"));
+        assert!(!matches_pattern("db.age", "prod/db.age"));
+    }
+
+    #[test]
+    fn enforces_constraints() -> Result<()> {
+        let policy = policy()?;
+        policy.check(
+            Path::new("/secrets/root.passwd.age"),
+            &public_keys(&[AGE, SSH_ED25519, PLUGIN]),
+        )?;
+        policy.check(Path::new("/secrets/prod/db.age"), &public_keys(&[AGE]))?;
+
+        let path = Path::new("/secrets/prod/root.passwd.age");
+        let keys = public_keys(&[SSH_ED25519, PLUGIN]);
+        assert_eq!(
+            policy.violations(path, &keys),
+            [
+                format!("recovery: not encrypted to required recipient {AGE}"),
+                format!("#2: encrypted to forbidden plugin recipient {PLUGIN}"),
+            ]
+        );
+        assert_eq!(
+            policy.check(path, &keys).unwrap_err().to_string(),
+            "The rule for prod/root.passwd.age violates the recipient policy"
+        );
+
+        assert_eq!(
+            policy.violations(
+                Path::new("/secrets/root.passwd.age"),
+                &public_keys(&[AGE, SSH_ED25519])
+            ),
+            ["humans: encrypted to 1 of the listed recipient(s), at least 2 required"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn scopes_constraints_to_their_rules_file() -> Result<()> {
+        let mut policy = policy()?;
+        policy.extend(Policy::from_json(
+            Path::new("/other"),
+            &json!({ "constraints": [ { "name": "other", "minRecipients": 3 } ] }),
+        )?);
+        assert_eq!(
+            policy.violations(Path::new("/other/db.age"), &public_keys(&[AGE])),
+            ["other: encrypted to 1 recipient(s), at least 3 required"]
+        );
+        assert_eq!(
+            policy.violations(Path::new("/secrets/db.age"), &public_keys(&[AGE])),
+            Vec::<String>::new()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_policies() {
+        assert!(Policy::from_json(Path::new("/"), &json!({ "constraint": [] })).is_err());
+        assert!(Policy::from_json(
+            Path::new("/"),
+            &json!({ "constraints": [ { "forbidTypes": [ "rot13" ] } ] })
+        )
+        .is_err());
+    }
+}
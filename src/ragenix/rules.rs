@@ -0,0 +1,123 @@
+//! The rules of a secrets file as Rust types, which the JSON schema of the rules is generated from
+
+use std::collections::BTreeMap;
+
+use schemars::{
+    gen::SchemaSettings,
+    schema::{ArrayValidation, InstanceType, Metadata, ObjectValidation, Schema, SchemaObject},
+    JsonSchema,
+};
+use serde::Deserialize;
+
+use crate::sidecar::SidecarKind;
+
+/// The rules of all secrets, keyed by the path of the secret relative to the rules file
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(transparent)]
+pub(crate) struct Rules(pub BTreeMap<String, Rule>);
+
+/// An age-encrypted file
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Rule {
+    #[schemars(schema_with = "public_keys_schema")]
+    pub public_keys: Vec<String>,
+    /// Whether agenix writes the file ASCII-armored, ragenix accepts it but always does
+    #[serde(default)]
+    #[allow(dead_code)] // Accepted for compatibility with agenix
+    pub armor: bool,
+    /// Plaintext files derived from the secret, relative to the rules file
+    #[serde(default)]
+    #[schemars(schema_with = "sidecars_schema")]
+    pub sidecars: BTreeMap<String, SidecarKind>,
+}
+
+/// A non-empty list of distinct recipients
+fn public_keys_schema(gen: &mut schemars::gen::SchemaGenerator) -> Schema {
+    let recipient = SchemaObject {
+        metadata: Some(Box::new(Metadata {
+            description: Some(
+                "An age-compatible recipient, e.g., an ed25519 SSH public key".to_string(),
+            ),
+            ..Metadata::default()
+        })),
+        ..gen.subschema_for::<String>().into_object()
+    };
+    SchemaObject {
+        instance_type: Some(InstanceType::Array.into()),
+        array: Some(Box::new(ArrayValidation {
+            items: Some(Schema::Object(recipient).into()),
+            min_items: Some(1),
+            unique_items: Some(true),
+            ..ArrayValidation::default()
+        })),
+        ..SchemaObject::default()
+    }
+    .into()
+}
+
+/// A map from sidecar paths to the names of their kinds
+fn sidecars_schema(_: &mut schemars::gen::SchemaGenerator) -> Schema {
+    let kind = SchemaObject {
+        metadata: Some(Box::new(Metadata {
+            description: Some(
+                "The kind of public information to derive from the secret".to_string(),
+            ),
+            ..Metadata::default()
+        })),
+        instance_type: Some(InstanceType::String.into()),
+        enum_values: Some(
+            SidecarKind::ALL
+                .iter()
+                .map(|kind| kind.to_string().into())
+                .collect(),
+        ),
+        ..SchemaObject::default()
+    };
+    SchemaObject {
+        instance_type: Some(InstanceType::Object.into()),
+        object: Some(Box::new(ObjectValidation {
+            additional_properties: Some(Box::new(kind.into())),
+            ..ObjectValidation::default()
+        })),
+        ..SchemaObject::default()
+    }
+    .into()
+}
+
+/// The JSON schema of [`Rules`] with all subschemas inlined, as printed by `--schema`
+pub(crate) fn schema_string() -> String {
+    // The generated keywords mean the same in draft 2020-12, which the schema always declared
+    let mut schema = SchemaSettings::draft07()
+        .with(|settings| {
+            settings.inline_subschemas = true;
+            settings.meta_schema = Some("https://json-schema.org/draft/2020-12/schema".to_string());
+        })
+        .into_generator()
+        .into_root_schema_for::<Rules>();
+    // The title would be the name of the transparent map type
+    let metadata = schema.schema.metadata();
+    metadata.title = None;
+    metadata.description = Some("Agenix secrets rules schema".to_string());
+    let mut string = serde_json::to_string_pretty(&schema).expect("Serializable schema!");
+    string.push('\n');
+    string
+}
+
+#[cfg(test)]
+mod test_rules {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn deserializes_sidecar_kinds() -> color_eyre::Result<()> {
+        for kind in SidecarKind::ALL {
+            assert_eq!(
+                serde_json::from_value::<SidecarKind>(kind.to_string().into())?,
+                SidecarKind::from_str(&kind.to_string())?
+            );
+        }
+        Ok(())
+    }
+}
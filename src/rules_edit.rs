@@ -0,0 +1,635 @@
+//! Syntax-preserving modifications of Nix rules files
+//!
+//! Rules files are parsed into a Nix syntax tree. Modifications are applied as text edits to the
+//! original source so that comments and formatting are kept.
+
+use std::collections::HashMap;
+
+use color_eyre::{
+    eyre::{eyre, Result},
+    Help,
+};
+use rnix::{
+    ast::{self, HasEntry, InterpolPart},
+    TextRange, TextSize,
+};
+use rowan::ast::AstNode;
+
+use crate::nix::quote;
+
+/// A rules file which can be modified while keeping its formatting
+pub(crate) struct RulesFile {
+    source: String,
+}
+
+/// A `publicKeys` expression of a rule
+struct PublicKeys {
+    /// The attribute name of the secret, relative to the rules file
+    secret: String,
+    expr: ast::Expr,
+}
+
+/// The contents of a string literal without interpolations
+fn string_literal(s: &ast::Str) -> Option<String> {
+    match s.normalized_parts().as_slice() {
+        [InterpolPart::Literal(literal)] => Some(literal.clone()),
+        [] => Some(String::new()),
+        _ => None,
+    }
+}
+
+/// The name of a static attribute, i.e., an identifier or a string literal
+fn attr_name(attr: &ast::Attr) -> Option<String> {
+    match attr {
+        ast::Attr::Ident(ident) => Some(ident.ident_token()?.text().to_string()),
+        ast::Attr::Str(s) => string_literal(s),
+        ast::Attr::Dynamic(_) => None,
+    }
+}
+
+fn attr_names(attrpath: &ast::Attrpath) -> Option<Vec<String>> {
+    attrpath.attrs().map(|attr| attr_name(&attr)).collect()
+}
+
+/// The whitespace preceding `offset` on its line
+fn indentation(source: &str, offset: TextSize) -> &str {
+    let line = &source[..usize::from(offset)];
+    let line_start = line.rfind('\n').map_or(0, |i| i + 1);
+    let line = &line[line_start..];
+    &line[..line.len() - line.trim_start().len()]
+}
+
+impl RulesFile {
+    pub(crate) fn parse(source: &str) -> Result<Self> {
+        let parse = rnix::Root::parse(source);
+        if let Some(err) = parse.errors().first() {
+            return Err(eyre!("Failed to parse rules file: {err}"));
+        }
+        Ok(Self {
+            source: source.to_string(),
+        })
+    }
+
+    pub(crate) fn source(&self) -> &str {
+        &self.source
+    }
+
+    fn root(&self) -> ast::Root {
+        rnix::Root::parse(&self.source).tree()
+    }
+
+    /// Descend into the attribute set the rules file evaluates to.
+    ///
+    /// Returns the `let` expressions passed on the way, too.
+    fn rules_attrset(&self) -> Result<(ast::AttrSet, Vec<ast::LetIn>)> {
+        let mut lets = Vec::new();
+        let mut expr = self.root().expr();
+        loop {
+            expr = match expr {
+                Some(ast::Expr::AttrSet(attrset)) => return Ok((attrset, lets)),
+                Some(ast::Expr::LetIn(let_in)) => {
+                    let body = let_in.body();
+                    lets.push(let_in);
+                    body
+                }
+                Some(ast::Expr::Lambda(lambda)) => lambda.body(),
+                Some(ast::Expr::Paren(paren)) => paren.expr(),
+                Some(ast::Expr::With(with)) => with.body(),
+                _ => {
+                    return Err(eyre!(
+                        "Rules file does not evaluate to an attribute set literal"
+                    ))
+                    .with_suggestion(|| "Edit the rules file manually");
+                }
+            }
+        }
+    }
+
+    /// The `let` bindings of the rules file
+    fn bindings(&self) -> Result<HashMap<String, ast::Expr>> {
+        let (_, lets) = self.rules_attrset()?;
+        let bindings = lets
+            .iter()
+            .flat_map(HasEntry::attrpath_values)
+            .filter_map(|entry| {
+                let names = attr_names(&entry.attrpath()?)?;
+                match names.as_slice() {
+                    [name] => Some((name.clone(), entry.value()?)),
+                    _ => None,
+                }
+            })
+            .collect();
+        Ok(bindings)
+    }
+
+    /// Whether `name` is bound with `let`
+    pub(crate) fn is_binding(&self, name: &str) -> Result<bool> {
+        Ok(self.bindings()?.contains_key(name))
+    }
+
+    /// The string value of each `let` binding which is bound to a string literal
+    pub(crate) fn string_bindings(&self) -> Result<HashMap<String, String>> {
+        Ok(self
+            .bindings()?
+            .into_iter()
+            .filter_map(|(name, expr)| match expr {
+                ast::Expr::Str(s) => Some((name, string_literal(&s)?)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// The `publicKeys` expressions of all rules
+    fn public_keys(&self) -> Result<Vec<PublicKeys>> {
+        let (attrset, _) = self.rules_attrset()?;
+        let mut public_keys = Vec::new();
+        for entry in attrset.attrpath_values() {
+            let (Some(names), Some(value)) = (
+                entry.attrpath().as_ref().and_then(attr_names),
+                entry.value(),
+            ) else {
+                continue;
+            };
+            match (names.as_slice(), value) {
+                ([secret, attr], expr) if attr == "publicKeys" => public_keys.push(PublicKeys {
+                    secret: secret.clone(),
+                    expr,
+                }),
+                ([secret], ast::Expr::AttrSet(rule)) => {
+                    let expr = rule.attrpath_values().find_map(|entry| {
+                        let names = attr_names(&entry.attrpath()?)?;
+                        (names == ["publicKeys"]).then(|| entry.value()).flatten()
+                    });
+                    if let Some(expr) = expr {
+                        public_keys.push(PublicKeys {
+                            secret: secret.clone(),
+                            expr,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(public_keys)
+    }
+
+    /// The names of all secrets with a rule
+    pub(crate) fn secrets(&self) -> Result<Vec<String>> {
+        Ok(self
+            .public_keys()?
+            .into_iter()
+            .map(|pk| pk.secret)
+            .collect())
+    }
+
+    /// Nix expression for a recipient: the name of a `let` binding if a binding with this name
+    /// or bound to this recipient exists, otherwise a string literal.
+    fn recipient_expr(
+        &self,
+        recipient: &str,
+        normalize: impl Fn(&str) -> String,
+    ) -> Result<String> {
+        let bindings = self.string_bindings()?;
+        if bindings.contains_key(recipient) {
+            return Ok(recipient.to_string());
+        }
+        let normalized = normalize(recipient);
+        let mut names = bindings
+            .into_iter()
+            .filter(|(_, value)| normalize(value) == normalized)
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        names.sort();
+        Ok(names.into_iter().next().unwrap_or_else(|| quote(recipient)))
+    }
+
+    /// Whether a list item denotes the given recipient
+    fn is_recipient(
+        &self,
+        item: &ast::Expr,
+        recipient: &str,
+        normalize: &impl Fn(&str) -> String,
+    ) -> Result<bool> {
+        let bindings = self.string_bindings()?;
+        let normalized = normalize(bindings.get(recipient).map_or(recipient, String::as_str));
+        Ok(match item {
+            ast::Expr::Str(s) => string_literal(s).is_some_and(|s| normalize(&s) == normalized),
+            ast::Expr::Ident(ident) => ident.ident_token().is_some_and(|token| {
+                let name = token.text();
+                name == recipient
+                    || bindings
+                        .get(name)
+                        .is_some_and(|value| normalize(value) == normalized)
+            }),
+            _ => false,
+        })
+    }
+
+    /// Apply text edits which must not overlap
+    fn apply(&mut self, mut edits: Vec<(TextRange, String)>) {
+        edits.sort_by_key(|(range, _)| range.start());
+        for (range, replacement) in edits.into_iter().rev() {
+            self.source
+                .replace_range(std::ops::Range::<usize>::from(range), &replacement);
+        }
+    }
+
+    /// Add a rule for `secret` with the given recipients.
+    ///
+    /// Recipients may be given as the name of a `let` binding, which may also be bound to a list of
+    /// recipients, or as a public key.
+    pub(crate) fn add_rule(
+        &mut self,
+        secret: &str,
+        recipients: &[String],
+        normalize: impl Fn(&str) -> String,
+    ) -> Result<()> {
+        if self.secrets()?.iter().any(|s| s == secret) {
+            return Err(eyre!("A rule for {secret} already exists"));
+        }
+
+        // Bindings of lists, e.g. `all`, are concatenated with a list of the other recipients
+        let bindings = self.bindings()?;
+        let mut lists = Vec::new();
+        let mut items = Vec::new();
+        for recipient in recipients {
+            if matches!(bindings.get(recipient), Some(ast::Expr::List(_))) {
+                lists.push(recipient.clone());
+            } else {
+                items.push(self.recipient_expr(recipient, &normalize)?);
+            }
+        }
+        if !items.is_empty() || lists.is_empty() {
+            lists.push(format!("[ {} ]", items.join(" ")));
+        }
+        let rule = format!("{}.publicKeys = {};", quote(secret), lists.join(" ++ "));
+
+        let (attrset, _) = self.rules_attrset()?;
+        let edit = if let Some(last) = attrset.entries().last() {
+            let range = last.syntax().text_range();
+            let indent = indentation(&self.source, range.start());
+            (TextRange::empty(range.end()), format!("\n{indent}{rule}"))
+        } else {
+            let indent = indentation(&self.source, attrset.syntax().text_range().start());
+            (
+                attrset.syntax().text_range(),
+                format!("{{\n{indent}  {rule}\n{indent}}}"),
+            )
+        };
+        self.apply(vec![edit]);
+
+        Ok(())
+    }
+
+    /// Add a recipient to the rules of the given secrets.
+    ///
+    /// Returns the secrets whose rules were modified.
+    pub(crate) fn add_recipient(
+        &mut self,
+        secrets: &[String],
+        recipient: &str,
+        normalize: impl Fn(&str) -> String,
+    ) -> Result<Vec<String>> {
+        let item = self.recipient_expr(recipient, &normalize)?;
+        let bindings = self.bindings()?;
+
+        let mut edits = Vec::new();
+        let mut modified = Vec::new();
+        for public_keys in self.public_keys()? {
+            if !secrets.contains(&public_keys.secret) {
+                continue;
+            }
+
+            // Look through references to lists bound with `let`
+            let list = match &public_keys.expr {
+                ast::Expr::List(list) => Some(list.clone()),
+                ast::Expr::Ident(ident) => ident
+                    .ident_token()
+                    .and_then(|token| bindings.get(token.text()).cloned())
+                    .and_then(|expr| ast::List::cast(expr.syntax().clone())),
+                _ => None,
+            };
+            if let Some(list) = &list {
+                let mut present = false;
+                for existing in list.items() {
+                    present |= self.is_recipient(&existing, recipient, &normalize)?;
+                }
+                if present {
+                    continue;
+                }
+            }
+
+            let range = public_keys.expr.syntax().text_range();
+            let edit = match &public_keys.expr {
+                ast::Expr::List(list) => {
+                    if let Some(last) = list.items().last() {
+                        let last_range = last.syntax().text_range();
+                        let multiline = self.source[range].contains('\n');
+                        let separator = if multiline {
+                            format!("\n{}", indentation(&self.source, last_range.start()))
+                        } else {
+                            " ".to_string()
+                        };
+                        (
+                            TextRange::empty(last_range.end()),
+                            format!("{separator}{item}"),
+                        )
+                    } else {
+                        (range, format!("[ {item} ]"))
+                    }
+                }
+                ast::Expr::Ident(_) | ast::Expr::Select(_) | ast::Expr::Paren(_) => {
+                    (TextRange::empty(range.end()), format!(" ++ [ {item} ]"))
+                }
+                expr => (range, format!("({}) ++ [ {item} ]", expr.syntax().text())),
+            };
+            edits.push(edit);
+            modified.push(public_keys.secret);
+        }
+        self.apply(edits);
+
+        Ok(modified)
+    }
+
+    /// Remove a recipient from the rules of the given secrets.
+    ///
+    /// The `publicKeys` of a rule may be a list, a reference to a list bound with `let` or a
+    /// concatenation of those with `++`. A reference to a list bound with `let` is replaced by
+    /// the remaining recipients of that list. Returns the secrets whose rules were modified.
+    pub(crate) fn remove_recipient(
+        &mut self,
+        secrets: &[String],
+        recipient: &str,
+        normalize: impl Fn(&str) -> String,
+    ) -> Result<Vec<String>> {
+        let bindings = self.bindings()?;
+
+        let mut edits = Vec::new();
+        let mut modified = Vec::new();
+        for public_keys in self.public_keys()? {
+            if !secrets.contains(&public_keys.secret) {
+                continue;
+            }
+
+            let removed = self.removal_edits(
+                &public_keys.expr,
+                recipient,
+                &normalize,
+                &bindings,
+                &mut edits,
+            )?;
+            match removed {
+                Some(true) => modified.push(public_keys.secret),
+                Some(false) => {}
+                None => {
+                    return Err(eyre!(
+                        "The publicKeys of {} are not a list or a concatenation of lists: {}",
+                        public_keys.secret,
+                        public_keys.expr.syntax().text()
+                    ))
+                    .with_suggestion(|| {
+                        "Bind the lists with `let` and concatenate them with `++` or edit the \
+                         rules file manually"
+                    });
+                }
+            }
+        }
+        self.apply(edits);
+
+        Ok(modified)
+    }
+
+    /// Collect the edits removing a recipient from a `publicKeys` expression.
+    ///
+    /// Returns whether the recipient was found, or `None` if the expression can't be edited.
+    fn removal_edits(
+        &self,
+        expr: &ast::Expr,
+        recipient: &str,
+        normalize: &impl Fn(&str) -> String,
+        bindings: &HashMap<String, ast::Expr>,
+        edits: &mut Vec<(TextRange, String)>,
+    ) -> Result<Option<bool>> {
+        match expr {
+            ast::Expr::List(list) => {
+                let mut removed = false;
+                for item in list.items() {
+                    if self.is_recipient(&item, recipient, normalize)? {
+                        edits.push((self.item_removal_range(&item), String::new()));
+                        removed = true;
+                    }
+                }
+                Ok(Some(removed))
+            }
+            ast::Expr::Ident(ident) => {
+                let list = ident
+                    .ident_token()
+                    .and_then(|token| bindings.get(token.text()).cloned())
+                    .and_then(|expr| ast::List::cast(expr.syntax().clone()));
+                let Some(list) = list else {
+                    return Ok(None);
+                };
+                let mut remaining = Vec::new();
+                let mut removed = false;
+                for item in list.items() {
+                    if self.is_recipient(&item, recipient, normalize)? {
+                        removed = true;
+                    } else {
+                        remaining.push(item.syntax().text().to_string());
+                    }
+                }
+                if removed {
+                    remaining.push("]".to_string());
+                    edits.push((
+                        expr.syntax().text_range(),
+                        format!("[ {}", remaining.join(" ")),
+                    ));
+                }
+                Ok(Some(removed))
+            }
+            ast::Expr::Paren(paren) => match paren.expr() {
+                Some(inner) => self.removal_edits(&inner, recipient, normalize, bindings, edits),
+                None => Ok(None),
+            },
+            ast::Expr::BinOp(op) if op.operator() == Some(ast::BinOpKind::Concat) => {
+                let (Some(lhs), Some(rhs)) = (op.lhs(), op.rhs()) else {
+                    return Ok(None);
+                };
+                let lhs = self.removal_edits(&lhs, recipient, normalize, bindings, edits)?;
+                let rhs = self.removal_edits(&rhs, recipient, normalize, bindings, edits)?;
+                Ok(lhs.zip(rhs).map(|(lhs, rhs)| lhs || rhs))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The range to remove for a list item, including its preceding whitespace or its line
+    fn item_removal_range(&self, item: &ast::Expr) -> TextRange {
+        let range = item.syntax().text_range();
+        let before = &self.source[..usize::from(range.start())];
+        let trimmed = before.trim_end_matches([' ', '\t']);
+        let start = if trimmed.ends_with('\n') {
+            trimmed.len() - 1
+        } else {
+            trimmed.len()
+        };
+        let start = TextSize::try_from(start).expect("Rules file is smaller than 4 GiB");
+        TextRange::new(start, range.end())
+    }
+}
+
+#[cfg(test)]
+mod test_rules_edit {
+    use indoc::indoc;
+
+    use super::*;
+
+    fn normalize(s: &str) -> String {
+        s.split_whitespace().take(2).collect::<Vec<_>>().join(" ")
+    }
+
+    const RULES: &str = indoc! {r#"
+        let
+          # Alice's laptop
+          alice = "ssh-ed25519 AAAAalice alice@laptop";
+          bob = "ssh-ed25519 AAAAbob";
+          all = [ alice bob ];
+        in
+        {
+          "a.age".publicKeys = [ alice ];
+          "b.age" = {
+            publicKeys = [
+              "ssh-ed25519 AAAAbob"
+              alice
+            ];
+            extra = "kept";
+          };
+          "c.age".publicKeys = all;
+        }
+    "#};
+
+    fn secrets() -> Vec<String> {
+        ["a.age", "b.age", "c.age"].map(String::from).to_vec()
+    }
+
+    #[test]
+    fn lists_secrets() -> Result<()> {
+        assert_eq!(RulesFile::parse(RULES)?.secrets()?, secrets());
+        Ok(())
+    }
+
+    #[test]
+    fn adds_rule() -> Result<()> {
+        let mut rules = RulesFile::parse(RULES)?;
+        rules.add_rule(
+            "d.age",
+            &[
+                "bob".to_string(),
+                "ssh-ed25519 AAAAalice".to_string(),
+                "age1xyz".to_string(),
+            ],
+            normalize,
+        )?;
+        rules.add_rule(
+            "e.age",
+            &["all".to_string(), "age1xyz".to_string()],
+            normalize,
+        )?;
+        assert!(rules.source().contains(
+            "  \"c.age\".publicKeys = all;\n  \"d.age\".publicKeys = [ bob alice \"age1xyz\" ];\n  \"e.age\".publicKeys = all ++ [ \"age1xyz\" ];\n}"
+        ));
+        assert!(rules.source().contains("# Alice's laptop"));
+        assert!(rules
+            .add_rule("d.age", &["bob".to_string()], normalize)
+            .is_err());
+
+        let mut rules = RulesFile::parse("{ }")?;
+        rules.add_rule("a.age", &["age1xyz".to_string()], normalize)?;
+        assert_eq!(
+            rules.source(),
+            "{\n  \"a.age\".publicKeys = [ \"age1xyz\" ];\n}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn adds_recipient() -> Result<()> {
+        let mut rules = RulesFile::parse(RULES)?;
+        let modified = rules.add_recipient(&secrets(), "ssh-ed25519 AAAAbob comment", normalize)?;
+        assert_eq!(modified, ["a.age"]);
+        assert!(rules
+            .source()
+            .contains("\"a.age\".publicKeys = [ alice bob ];"));
+
+        let modified = rules.add_recipient(&secrets(), "age1xyz", normalize)?;
+        assert_eq!(modified, secrets());
+        assert!(rules
+            .source()
+            .contains("\"a.age\".publicKeys = [ alice bob \"age1xyz\" ];"));
+        assert!(rules
+            .source()
+            .contains("      alice\n      \"age1xyz\"\n    ];"));
+        assert!(rules
+            .source()
+            .contains("\"c.age\".publicKeys = all ++ [ \"age1xyz\" ];"));
+        Ok(())
+    }
+
+    #[test]
+    fn removes_recipient() -> Result<()> {
+        let mut rules = RulesFile::parse(RULES)?;
+        let modified = rules.remove_recipient(&secrets(), "bob", normalize)?;
+        assert_eq!(modified, ["b.age", "c.age"]);
+        assert!(rules
+            .source()
+            .contains("    publicKeys = [\n      alice\n    ];"));
+        assert!(rules.source().contains("\"c.age\".publicKeys = [ alice ];"));
+        assert!(rules.source().contains("all = [ alice bob ];"));
+
+        let modified = rules.remove_recipient(&["a.age".to_string()], "bob", normalize)?;
+        assert_eq!(modified, Vec::<String>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn removes_recipient_from_concatenations() -> Result<()> {
+        let mut rules = RulesFile::parse(indoc! {r#"
+            let
+              alice = "ssh-ed25519 AAAAalice";
+              bob = "ssh-ed25519 AAAAbob";
+              users = [ alice ];
+              systems = [ bob ];
+            in
+            {
+              "a.age".publicKeys = users ++ systems;
+              "b.age".publicKeys = (users ++ [ bob "age1xyz" ]);
+              "c.age".publicKeys = builtins.attrValues { inherit alice bob; };
+            }
+        "#})?;
+        let modified = rules.remove_recipient(
+            &["a.age".to_string(), "b.age".to_string()],
+            "bob",
+            normalize,
+        )?;
+        assert_eq!(modified, ["a.age", "b.age"]);
+        assert!(rules
+            .source()
+            .contains("\"a.age\".publicKeys = users ++ [ ];"));
+        assert!(rules
+            .source()
+            .contains("\"b.age\".publicKeys = (users ++ [ \"age1xyz\" ]);"));
+
+        let report = rules
+            .remove_recipient(&["c.age".to_string()], "bob", normalize)
+            .unwrap_err();
+        assert!(report
+            .to_string()
+            .starts_with("The publicKeys of c.age are not a list or a concatenation of lists"));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_nix() {
+        assert!(RulesFile::parse("{ \"a.age\".publicKeys = [ ; }").is_err());
+    }
+}
@@ -0,0 +1,329 @@
+//! Derive plaintext sidecar files, e.g., public keys or password hashes, from secrets
+
+use std::{
+    fmt, fs,
+    io::{self, BufReader, Write},
+    os::unix::prelude::PermissionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine};
+use color_eyre::{
+    eyre::{eyre, Result, WrapErr},
+    Help,
+};
+use sha_crypt::ShaCrypt;
+use tempfile::NamedTempFile;
+use yescrypt::{PasswordHasher, PasswordVerifier, Yescrypt};
+
+/// The kind of public information to derive from a secret
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SidecarKind {
+    /// OpenSSH public key of an OpenSSH private key
+    OpensshPublicKey,
+    /// Public key of a base64-encoded private key as generated by `wg genkey`
+    WireguardPublicKey,
+    /// age recipient of an `AGE-SECRET-KEY-1` identity
+    AgeRecipient,
+    /// SHA-512 crypt(3) hash of a password
+    Sha512Crypt,
+    /// yescrypt crypt(3) hash of a password
+    Yescrypt,
+}
+
+impl SidecarKind {
+    pub(crate) const ALL: [Self; 5] = [
+        Self::OpensshPublicKey,
+        Self::WireguardPublicKey,
+        Self::AgeRecipient,
+        Self::Sha512Crypt,
+        Self::Yescrypt,
+    ];
+}
+
+impl FromStr for SidecarKind {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        serde_json::from_value(s.into())
+            .map_err(|_| eyre!("Unknown sidecar type: {s}"))
+            .with_suggestion(|| {
+                let kinds: Vec<_> = Self::ALL.iter().map(ToString::to_string).collect();
+                format!("Use one of {}", kinds.join(", "))
+            })
+    }
+}
+
+impl fmt::Display for SidecarKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(s)) => f.write_str(&s),
+            _ => unreachable!("Sidecar kinds serialize to strings"),
+        }
+    }
+}
+
+/// A plaintext file derived from a secret
+#[derive(Debug, Clone)]
+pub(crate) struct Sidecar {
+    pub path: PathBuf,
+    pub kind: SidecarKind,
+}
+
+/// Whether a sidecar file matches the secret it was derived from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SidecarStatus {
+    UpToDate,
+    Stale,
+    Missing,
+}
+
+impl fmt::Display for SidecarStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::UpToDate => "up-to-date",
+            Self::Stale => "stale",
+            Self::Missing => "missing",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Password hashes are salted, so the passwords are taken without their trailing newline
+fn password(plaintext: &[u8]) -> Result<&str> {
+    std::str::from_utf8(plaintext)
+        .map(|s| s.trim_end_matches(['\r', '\n']))
+        .wrap_err("Password is not valid UTF-8")
+}
+
+/// The first line of a plaintext which isn't empty or a comment
+fn first_line(plaintext: &[u8]) -> Result<&str> {
+    std::str::from_utf8(plaintext)?
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty() && !l.starts_with('#'))
+        .ok_or_else(|| eyre!("Secret is empty"))
+}
+
+fn openssh_public_key(plaintext: &[u8]) -> Result<String> {
+    let identity = age::ssh::Identity::from_buffer(BufReader::new(plaintext), None)
+        .wrap_err("Secret is not an OpenSSH private key")?;
+    let recipient = age::ssh::Recipient::try_from(identity)
+        .map_err(|err| eyre!("Unsupported OpenSSH private key: {err:?}"))?;
+    Ok(recipient.to_string())
+}
+
+fn wireguard_public_key(plaintext: &[u8]) -> Result<String> {
+    let private_key: [u8; 32] = BASE64_STANDARD
+        .decode(first_line(plaintext)?)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| eyre!("Secret is not a base64-encoded WireGuard private key"))?;
+    let public_key = x25519_dalek::x25519(private_key, x25519_dalek::X25519_BASEPOINT_BYTES);
+    Ok(BASE64_STANDARD.encode(public_key))
+}
+
+fn age_recipient(plaintext: &[u8]) -> Result<String> {
+    let identity = first_line(plaintext)?
+        .parse::<age::x25519::Identity>()
+        .map_err(|err| eyre!("Secret is not an age identity: {err}"))?;
+    Ok(identity.to_public().to_string())
+}
+
+impl Sidecar {
+    /// Derive the contents of the sidecar file from the plaintext of its secret.
+    ///
+    /// Password hashes use a random salt. Thus, deriving them twice yields different results.
+    fn derive(&self, plaintext: &[u8]) -> Result<String> {
+        match self.kind {
+            SidecarKind::OpensshPublicKey => openssh_public_key(plaintext),
+            SidecarKind::WireguardPublicKey => wireguard_public_key(plaintext),
+            SidecarKind::AgeRecipient => age_recipient(plaintext),
+            SidecarKind::Sha512Crypt => ShaCrypt::SHA512
+                .hash_password(password(plaintext)?.as_bytes())
+                .map(|hash| hash.to_string())
+                .map_err(|err| eyre!("Failed to hash password: {err}")),
+            SidecarKind::Yescrypt => Yescrypt::default()
+                .hash_password(password(plaintext)?.as_bytes())
+                .map(|hash| hash.to_string())
+                .map_err(|err| eyre!("Failed to hash password: {err}")),
+        }
+        .wrap_err_with(|| format!("Failed to derive {} for {}", self.kind, self.path.display()))
+    }
+
+    /// Compare the sidecar file on disk with the plaintext of its secret
+    pub(crate) fn status(&self, plaintext: &[u8]) -> Result<SidecarStatus> {
+        let current = match fs::read_to_string(&self.path) {
+            Ok(current) => current,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(SidecarStatus::Missing);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let current = current.trim();
+
+        let up_to_date = match self.kind {
+            SidecarKind::Sha512Crypt => ShaCrypt::SHA512
+                .verify_password(password(plaintext)?.as_bytes(), current)
+                .is_ok(),
+            SidecarKind::Yescrypt => Yescrypt::default()
+                .verify_password(password(plaintext)?.as_bytes(), current)
+                .is_ok(),
+            _ => self.derive(plaintext)? == current,
+        };
+
+        Ok(if up_to_date {
+            SidecarStatus::UpToDate
+        } else {
+            SidecarStatus::Stale
+        })
+    }
+
+    /// Write the sidecar file unless it is already up-to-date.
+    ///
+    /// Returns whether the file was written. The file is created with a mode of `0o644`. It is
+    /// replaced atomically and never written through a symbolic link.
+    pub(crate) fn update(&self, plaintext: &[u8]) -> Result<bool> {
+        if fs::symlink_metadata(&self.path).is_ok_and(|meta| meta.file_type().is_symlink()) {
+            return Err(eyre!(
+                "Refusing to write the sidecar {} as it is a symbolic link",
+                self.path.display()
+            ))
+            .with_suggestion(|| "Remove the symbolic link");
+        }
+        if self.status(plaintext)? == SidecarStatus::UpToDate {
+            return Ok(false);
+        }
+
+        let contents = self.derive(plaintext)?;
+        let dir = self.path.parent().unwrap_or_else(|| Path::new("."));
+        let mut file = NamedTempFile::new_in(dir)?;
+        file.write_all(format!("{contents}\n").as_bytes())?;
+        file.as_file()
+            .set_permissions(PermissionsExt::from_mode(0o644))?;
+        file.persist(&self.path)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test_sidecar {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn sidecar(dir: &TempDir, kind: SidecarKind) -> Sidecar {
+        Sidecar {
+            path: dir.path().join("sidecar"),
+            kind,
+        }
+    }
+
+    #[test]
+    fn parses_kinds() -> Result<()> {
+        for kind in SidecarKind::ALL {
+            assert_eq!(kind.to_string().parse::<SidecarKind>()?, kind);
+        }
+        assert!("ssh-public-key".parse::<SidecarKind>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn derives_openssh_public_key() -> Result<()> {
+        let base_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let plaintext = fs::read(base_dir.join("example/keys/id_ed25519"))?;
+        let dir = tempfile::tempdir()?;
+        let sidecar = sidecar(&dir, SidecarKind::OpensshPublicKey);
+        assert_eq!(
+            sidecar.derive(&plaintext)?,
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILoPdkEfhcsmW6Lg86GMrEJZnYfFBb7fL9G/IXK7pDQd"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn derives_wireguard_public_key() -> Result<()> {
+        // Test vector from RFC 7748, section 6.1
+        let private_key = BASE64_STANDARD.encode(hex_literal::hex!(
+            "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a"
+        ));
+        let public_key = BASE64_STANDARD.encode(hex_literal::hex!(
+            "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"
+        ));
+        let dir = tempfile::tempdir()?;
+        let sidecar = sidecar(&dir, SidecarKind::WireguardPublicKey);
+        assert_eq!(
+            sidecar.derive(format!("{private_key}\n").as_bytes())?,
+            public_key
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn derives_age_recipient() -> Result<()> {
+        let plaintext = indoc::indoc! {"
+            # created: 2021-09-20T23:41:59+02:00
+            # public key: age1fjc9tyguvxfqh2ey2qqfc066g3gee7hlnhqn2g7yn4f6smymmsnq6xdn2t
+            AGE-SECRET-KEY-1C744H5LMUVHGVLX8HXAWA9ENXXXJ6R6F89V5AGEDXXD8GECQ624QQUXKHX
+        "};
+        let dir = tempfile::tempdir()?;
+        let sidecar = sidecar(&dir, SidecarKind::AgeRecipient);
+        assert_eq!(
+            sidecar.derive(plaintext.as_bytes())?,
+            "age1fjc9tyguvxfqh2ey2qqfc066g3gee7hlnhqn2g7yn4f6smymmsnq6xdn2t"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn updates_only_stale_password_hashes() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for kind in [SidecarKind::Sha512Crypt, SidecarKind::Yescrypt] {
+            let sidecar = sidecar(&dir, kind);
+            let _ = fs::remove_file(&sidecar.path);
+
+            assert_eq!(sidecar.status(b"wurzelpfropf\n")?, SidecarStatus::Missing);
+            assert!(sidecar.update(b"wurzelpfropf\n")?);
+            let hash = fs::read_to_string(&sidecar.path)?;
+
+            assert_eq!(sidecar.status(b"wurzelpfropf")?, SidecarStatus::UpToDate);
+            assert!(!sidecar.update(b"wurzelpfropf")?);
+            assert_eq!(fs::read_to_string(&sidecar.path)?, hash);
+
+            assert_eq!(sidecar.status(b"yaxifaxi")?, SidecarStatus::Stale);
+            assert!(sidecar.update(b"yaxifaxi")?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn detects_stale_public_key() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sidecar = sidecar(&dir, SidecarKind::AgeRecipient);
+        let plaintext =
+            b"AGE-SECRET-KEY-1C744H5LMUVHGVLX8HXAWA9ENXXXJ6R6F89V5AGEDXXD8GECQ624QQUXKHX";
+
+        fs::write(
+            &sidecar.path,
+            "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk\n",
+        )?;
+        assert_eq!(sidecar.status(plaintext)?, SidecarStatus::Stale);
+        assert!(sidecar.update(plaintext)?);
+        assert_eq!(sidecar.status(plaintext)?, SidecarStatus::UpToDate);
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_to_follow_symlinks() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let target = dir.path().join("target");
+        fs::write(&target, "precious\n")?;
+        let sidecar = sidecar(&dir, SidecarKind::Sha512Crypt);
+        std::os::unix::fs::symlink(&target, &sidecar.path)?;
+
+        assert!(sidecar.update(b"wurzelpfropf").is_err());
+        assert_eq!(fs::read_to_string(&target)?, "precious\n");
+        Ok(())
+    }
+}
@@ -1,8 +1,10 @@
 //! Util functions
 
 use std::{
-    fs::File,
+    env,
+    fs::{self, File},
     io,
+    os::unix::fs::PermissionsExt,
     path::{Component, Path, PathBuf},
 };
 
@@ -170,3 +172,158 @@ mod test_split_editor {
         assert_eq!(result.unwrap_err().to_string(), "Editor is empty");
     }
 }
+
+/// Find the executable `name` in the directories of the `PATH` environment variable
+pub(crate) fn find_executable(name: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| {
+            fs::metadata(path).is_ok_and(|metadata| {
+                metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+            })
+        })
+}
+
+/// Find the file `name` in `start` or the nearest of its ancestors.
+///
+/// The search stops at the root of a Git repository, i.e., a directory containing `.git`, or at
+/// the root of the file system.
+pub(crate) fn find_upwards(start: &Path, name: &str) -> Option<PathBuf> {
+    for dir in start.ancestors() {
+        let candidate = dir.join(name);
+        if candidate.is_file() {
+            return Some(candidate);
+        }
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+    None
+}
+
+/// The root of the Git repository containing `dir`, i.e., the nearest ancestor containing `.git`,
+/// or `dir` itself outside of a repository
+pub(crate) fn repository_root(dir: &Path) -> PathBuf {
+    dir.ancestors()
+        .find(|ancestor| ancestor.join(".git").exists())
+        .unwrap_or(dir)
+        .to_path_buf()
+}
+
+/// The number of single character insertions, deletions and substitutions turning `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(a != *b);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The candidate `name` is most likely a misspelling of, if any is close enough.
+///
+/// Case differences don't count, so `publickeys` matches `publicKeys`.
+pub(crate) fn closest_match<'a>(name: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let name = name.to_lowercase();
+    candidates
+        .iter()
+        .map(|candidate| (edit_distance(&name, &candidate.to_lowercase()), *candidate))
+        .filter(|(distance, candidate)| *distance <= (candidate.chars().count() / 3).max(1))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Find all files called `name` in `dir` and its subdirectories, skipping hidden directories.
+///
+/// Symbolic links to directories are not followed. The result is sorted.
+pub(crate) fn find_recursive(dir: &Path, name: &str) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(fs::DirEntry::file_name);
+    for entry in entries {
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        if file_type.is_dir() {
+            if !entry.file_name().to_string_lossy().starts_with('.') {
+                found.extend(find_recursive(&path, name)?);
+            }
+        } else if entry.file_name() == name {
+            found.push(path);
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod test_find {
+
+    use super::*;
+
+    #[test]
+    fn finds_files_recursively() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for subdir in ["hosts/wurzel", "hosts/pfropf", ".git/secrets"] {
+            fs::create_dir_all(dir.path().join(subdir))?;
+            fs::write(dir.path().join(subdir).join("secrets.nix"), "{ }")?;
+        }
+        fs::write(dir.path().join("secrets.nix"), "{ }")?;
+        fs::write(dir.path().join("hosts/other.nix"), "{ }")?;
+        assert_eq!(
+            find_recursive(dir.path(), "secrets.nix")?,
+            [
+                dir.path().join("hosts/pfropf/secrets.nix"),
+                dir.path().join("hosts/wurzel/secrets.nix"),
+                dir.path().join("secrets.nix"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn finds_nearest_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let nested = dir.path().join("repo/hosts/wurzel");
+        fs::create_dir_all(&nested)?;
+        fs::create_dir(dir.path().join("repo/.git"))?;
+        fs::write(dir.path().join("secrets.nix"), "{ }")?;
+        assert_eq!(find_upwards(&nested, "secrets.nix"), None);
+
+        fs::write(dir.path().join("repo/secrets.nix"), "{ }")?;
+        assert_eq!(
+            find_upwards(&nested, "secrets.nix"),
+            Some(dir.path().join("repo/secrets.nix"))
+        );
+
+        fs::write(dir.path().join("repo/hosts/secrets.nix"), "{ }")?;
+        assert_eq!(
+            find_upwards(&nested, "secrets.nix"),
+            Some(dir.path().join("repo/hosts/secrets.nix"))
+        );
+        assert_eq!(repository_root(&nested), dir.path().join("repo"));
+        assert_eq!(repository_root(dir.path()), dir.path());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_closest_match {
+    use super::*;
+
+    #[test]
+    fn suggests_similar_names() {
+        let candidates = ["publicKeys", "sidecars"];
+        assert_eq!(edit_distance("publicKey", "publicKeys"), 1);
+        assert_eq!(edit_distance("sidcars", "sidecars"), 1);
+        assert_eq!(closest_match("publickeys", &candidates), Some("publicKeys"));
+        assert_eq!(closest_match("publicKey", &candidates), Some("publicKeys"));
+        assert_eq!(closest_match("pubKeys", &candidates), Some("publicKeys"));
+        assert_eq!(closest_match("sidecar", &candidates), Some("sidecars"));
+        assert_eq!(closest_match("extra", &candidates), None);
+    }
+}
@@ -6,6 +6,7 @@ use predicates::prelude::*;
 use std::{
     fs,
     io::Write,
+    os::unix::prelude::PermissionsExt,
     path::{Path, PathBuf},
 };
 use tempfile::TempDir;
@@ -94,7 +95,7 @@ fn edit_new_entry() -> Result<()> {
         .current_dir(dir.path())
         .arg("--edit")
         .arg("pandora.age")
-        .env("EDITOR", format!("cp {}", &pandora.display()))
+        .env("EDITOR", format!("cp {}", pandora.display()))
         .assert();
 
     assert.success().stdout("");
@@ -367,6 +368,1258 @@ fn rekeying_fails_no_valid_identites() -> Result<()> {
     Ok(())
 }
 
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn edit_writes_sidecars() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let rules = indoc! {r#"
+    {
+        "age.key.age" = {
+            publicKeys = [
+                "age1fjc9tyguvxfqh2ey2qqfc066g3gee7hlnhqn2g7yn4f6smymmsnq6xdn2t"
+            ];
+            sidecars."age.key.pub" = "age-recipient";
+        };
+    }
+    "#};
+    fs::write(dir.path().join("secrets.nix"), rules)?;
+
+    let privkey_path = dir.path().join("key.txt");
+    fs::write(
+        &privkey_path,
+        "AGE-SECRET-KEY-1C744H5LMUVHGVLX8HXAWA9ENXXXJ6R6F89V5AGEDXXD8GECQ624QQUXKHX\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(dir.path())
+        .arg("--edit")
+        .arg("age.key.age")
+        .env("EDITOR", "-")
+        .pipe_stdin(&privkey_path)?
+        .assert();
+
+    let sidecar_path = fs::canonicalize(dir.path())?.join("age.key.pub");
+    assert
+        .success()
+        .stdout(format!("Updated sidecar {}\n", sidecar_path.display()));
+    assert_eq!(
+        fs::read_to_string(&sidecar_path)?,
+        "age1fjc9tyguvxfqh2ey2qqfc066g3gee7hlnhqn2g7yn4f6smymmsnq6xdn2t\n"
+    );
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(dir.path())
+        .arg("--check-sidecars")
+        .arg("--identity")
+        .arg(&privkey_path)
+        .assert();
+    assert.success().stdout("");
+
+    fs::write(&sidecar_path, "age1wurzelpfropf\n")?;
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(dir.path())
+        .arg("--check-sidecars")
+        .arg("--identity")
+        .arg(&privkey_path)
+        .assert();
+    assert
+        .failure()
+        .stdout(predicate::str::contains("age.key.pub of "))
+        .stdout(predicate::str::ends_with("is stale\n"));
+
+    Ok(())
+}
+
+#[test]
+fn keygen_works() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let identity_path = dir.path().join("key.txt");
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd.arg("--keygen").arg(&identity_path).assert();
+
+    let output = assert.success().get_output().stdout.clone();
+    let recipient = String::from_utf8(output)?;
+    assert!(predicate::str::starts_with("age1").eval(&recipient));
+
+    let identity = fs::read_to_string(&identity_path)?;
+    assert!(predicate::str::contains(format!("# public key: {recipient}")).eval(&identity));
+    assert!(predicate::str::contains("AGE-SECRET-KEY-1").eval(&identity));
+
+    let mode = fs::metadata(&identity_path)?.permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    // Never overwrite an existing identity
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd.arg("--keygen").arg(&identity_path).assert();
+    assert.failure();
+    assert_eq!(fs::read_to_string(&identity_path)?, identity);
+
+    Ok(())
+}
+
+#[test]
+fn convert_works() -> Result<()> {
+    let base_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let recipient = "age1hufdzlad7ntsley9q99n3dw5x492kg2j62nf7zt7jqa7yt3fsgtqdyeg30";
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .arg("--convert")
+        .write_stdin(
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILoPdkEfhcsmW6Lg86GMrEJZnYfFBb7fL9G/IXK7pDQd\n",
+        )
+        .assert();
+    assert.success().stdout(format!("{recipient}\n"));
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .arg("--convert")
+        .arg(base_dir.join("example/keys/id_ed25519"))
+        .assert();
+    assert.success().stdout(formatdoc! {"
+        # public key: {recipient}
+        AGE-SECRET-KEY-1LPZLTMH80V6M6CKCW3CV35AXHHAS7CLUW9YUGJ5DFHTLKCTJC49QGFDEW5
+    "});
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn init_works() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pubkey_path = dir.path().join("authorized_keys");
+    fs::write(
+        &pubkey_path,
+        indoc! {"
+            ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILoPdkEfhcsmW6Lg86GMrEJZnYfFBb7fL9G/IXK7pDQd wurzel@pfropf
+            age1fjc9tyguvxfqh2ey2qqfc066g3gee7hlnhqn2g7yn4f6smymmsnq6xdn2t
+        "},
+    )?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(dir.path())
+        .arg("--init")
+        .arg(&pubkey_path)
+        .assert();
+    assert
+        .success()
+        .stdout("Wrote ./secrets.nix with 2 recipient(s)\n");
+
+    let rules = fs::read_to_string(dir.path().join("secrets.nix"))?;
+    assert!(predicate::str::contains("wurzel-pfropf = \"ssh-ed25519 ").eval(&rules));
+    assert!(predicate::str::contains("all = [ wurzel-pfropf authorized_keys ];").eval(&rules));
+
+    // Refuses to overwrite the rules file
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(dir.path())
+        .arg("--init")
+        .arg(&pubkey_path)
+        .assert();
+    assert.failure();
+
+    // The example rule is usable right away
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(dir.path())
+        .arg("--edit")
+        .arg("secret.age")
+        .env("EDITOR", "-")
+        .write_stdin("wurzelpfropf")
+        .assert();
+    assert.success();
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn modifies_rules() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let rules_path = dir.path().join("secrets.nix");
+    fs::write(
+        &rules_path,
+        indoc! {r#"
+            let
+              # Wurzelpfropf's laptop
+              wurzel = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILoPdkEfhcsmW6Lg86GMrEJZnYfFBb7fL9G/IXK7pDQd";
+            in
+            {
+              "a.age".publicKeys = [ wurzel ];
+            }
+        "#},
+    )?;
+    let age_recipient = "age1fjc9tyguvxfqh2ey2qqfc066g3gee7hlnhqn2g7yn4f6smymmsnq6xdn2t";
+
+    // Doesn't create a rule without consent if stdin is not a terminal
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(dir.path())
+        .arg("--edit")
+        .arg("b.age")
+        .arg("--recipient")
+        .arg("wurzel")
+        .env("EDITOR", "-")
+        .write_stdin("wurzelpfropf")
+        .assert();
+    assert
+        .failure()
+        .stderr(predicate::str::contains("pass --yes to agree"));
+    assert!(!dir.path().join("b.age").exists());
+
+    // Creates the rule when editing an unknown file
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(dir.path())
+        .arg("--edit")
+        .arg("b.age")
+        .arg("--recipient")
+        .arg("wurzel")
+        .arg("--yes")
+        .env("EDITOR", "-")
+        .write_stdin("wurzelpfropf")
+        .assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains("Added rule for b.age"));
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(dir.path())
+        .arg("--add-recipient")
+        .arg(age_recipient)
+        .arg("--secret")
+        .arg("b.age")
+        .assert();
+    assert
+        .success()
+        .stdout("Added recipient to b.age\nRun --rekey to re-encrypt the secrets\n");
+
+    let rules = fs::read_to_string(&rules_path)?;
+    assert!(predicate::str::contains("# Wurzelpfropf's laptop").eval(&rules));
+    assert!(predicate::str::contains(format!(
+        "  \"b.age\".publicKeys = [ wurzel \"{age_recipient}\" ];"
+    ))
+    .eval(&rules));
+
+    // Invalid recipients leave the rules untouched
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(dir.path())
+        .arg("--add-recipient")
+        .arg("wurzelpfropf")
+        .assert();
+    assert.failure();
+    assert_eq!(fs::read_to_string(&rules_path)?, rules);
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(dir.path())
+        .arg("--remove-recipient")
+        .arg(age_recipient)
+        .assert();
+    assert
+        .success()
+        .stdout("Removed recipient from b.age\nRun --rekey to re-encrypt the secrets\n");
+
+    // Rules without any recipients are invalid
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(dir.path())
+        .arg("--remove-recipient")
+        .arg("wurzel")
+        .assert();
+    assert.failure();
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn modifies_rules_given_as_bare_file_name() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let rules_path = dir.path().join("secrets.nix");
+    fs::write(
+        &rules_path,
+        indoc! {r#"
+            let
+              wurzel = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILoPdkEfhcsmW6Lg86GMrEJZnYfFBb7fL9G/IXK7pDQd";
+            in
+            {
+              "a.age".publicKeys = [ wurzel ];
+            }
+        "#},
+    )?;
+    let age_recipient = "age1fjc9tyguvxfqh2ey2qqfc066g3gee7hlnhqn2g7yn4f6smymmsnq6xdn2t";
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(dir.path())
+        .arg("--rules")
+        .arg("secrets.nix")
+        .arg("--add-rule")
+        .arg("b.age")
+        .arg("--recipient")
+        .arg("wurzel")
+        .assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains("Added rule for b.age"));
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(dir.path())
+        .arg("--rules")
+        .arg("secrets.nix")
+        .arg("--add-recipient")
+        .arg(age_recipient)
+        .arg("--secret")
+        .arg("b.age")
+        .assert();
+    assert
+        .success()
+        .stdout("Added recipient to b.age\nRun --rekey to re-encrypt the secrets\n");
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(dir.path())
+        .arg("--rules")
+        .arg("secrets.nix")
+        .arg("--remove-recipient")
+        .arg(age_recipient)
+        .arg("--secret")
+        .arg("b.age")
+        .assert();
+    assert
+        .success()
+        .stdout("Removed recipient from b.age\nRun --rekey to re-encrypt the secrets\n");
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn revoke_works() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--revoke")
+        .arg("sshRsa")
+        .arg("--secret")
+        .arg("root.passwd.age")
+        .arg("--identity")
+        .arg("keys/id_ed25519")
+        .env("EDITOR", "sed -i s/^/rotated-/")
+        .assert();
+    assert.success().stdout(formatdoc! {"
+        Rekeying {path}
+        The revoked recipient could read the following secrets, rotate them:
+          root.passwd.age
+    ", path = path.join("root.passwd.age").display()});
+
+    let rules = fs::read_to_string(path.join("secrets.nix"))?;
+    assert!(predicate::str::contains("publicKeys = [ age sshEd25519 ];").eval(&rules));
+    assert!(predicate::str::contains(
+        "\"github-runner.token.age\".publicKeys = [ age sshEd25519 sshRsa ];"
+    )
+    .eval(&rules));
+
+    // The secret was rekeyed and rotated
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd.current_dir(&path).arg("--check-headers").assert();
+    assert.success();
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--edit")
+        .arg("root.passwd.age")
+        .arg("--identity")
+        .arg("keys/id_ed25519")
+        .env("EDITOR", "cat")
+        .assert();
+    assert
+        .success()
+        .stdout(predicate::str::starts_with("rotated-"));
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn revoke_can_be_resumed() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+    let rules = fs::read_to_string(path.join("secrets.nix"))?;
+    // An identity which can't decrypt the secrets
+    fs::write(
+        path.join("wrong.txt"),
+        "AGE-SECRET-KEY-1C744H5LMUVHGVLX8HXAWA9ENXXXJ6R6F89V5AGEDXXD8GECQ624QQUXKHX\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--revoke")
+        .arg("sshRsa")
+        .arg("--identity")
+        .arg("wrong.txt")
+        .env("EDITOR", "sed -i s/^/rotated-/")
+        .assert();
+    assert
+        .failure()
+        .stderr(predicate::str::contains("Failed to rekey"));
+    assert_eq!(fs::read_to_string(path.join("secrets.nix"))?, rules);
+    assert!(!fs::read_dir(&path)?.any(|entry| entry
+        .is_ok_and(|entry| entry.file_name().to_string_lossy().starts_with(".ragenix-"))));
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--revoke")
+        .arg("sshRsa")
+        .arg("--identity")
+        .arg("keys/id_ed25519")
+        .env("EDITOR", "sed -i s/^/rotated-/")
+        .assert();
+    assert.success().stdout(predicate::str::contains(
+        "The revoked recipient could read the following secrets, rotate them:",
+    ));
+    let rules = fs::read_to_string(path.join("secrets.nix"))?;
+    assert!(!predicate::str::contains("sshRsa ]").eval(&rules));
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn lint_works() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+    let identity = path.join("keys/id_ed25519");
+    fs::set_permissions(&identity, fs::Permissions::from_mode(0o600))?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--lint")
+        .arg("--identity")
+        .arg(&identity)
+        .env("HOME", &path)
+        .assert();
+    assert.success().stdout("");
+
+    fs::set_permissions(&identity, fs::Permissions::from_mode(0o644))?;
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--lint")
+        .arg("--identity")
+        .arg(&identity)
+        .env("HOME", &path)
+        .assert();
+    assert.failure().code(1).stdout(formatdoc! {"
+        error[identity-permissions]: identity {} is accessible by group or others (mode 644)
+        1 finding(s), 1 error(s)
+    ", identity.display()});
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--lint")
+        .arg("--identity")
+        .arg(&identity)
+        .arg("--allow")
+        .arg("identity-permissions")
+        .env("HOME", &path)
+        .assert();
+    assert.success().stdout("");
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn policy_blocks_rekeying() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+    fs::write(
+        path.join("ragenix-policy.json"),
+        r#"{ "constraints": [ { "name": "no-rsa", "secrets": [ "root.*" ], "forbidTypes": [ "ssh-rsa" ] } ] }"#,
+    )?;
+    let before = fs::read(path.join("github-runner.token.age"))?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--rekey")
+        .arg("--identity")
+        .arg("keys/id_ed25519")
+        .assert();
+    assert.failure().stdout("").stderr(
+        predicate::str::contains("The rule for root.passwd.age violates the recipient policy").and(
+            predicate::str::contains("no-rsa: encrypted to forbidden ssh-rsa recipient"),
+        ),
+    );
+    assert_eq!(fs::read(path.join("github-runner.token.age"))?, before);
+
+    fs::write(path.join("empty.json"), r#"{ "constraints": [] }"#)?;
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--rekey")
+        .arg("--identity")
+        .arg("keys/id_ed25519")
+        .arg("--policy")
+        .arg("empty.json")
+        .assert();
+    assert.success();
+
+    Ok(())
+}
+
+#[test]
+fn inspect_works() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+    fs::create_dir(path.join(".ssh"))?;
+    fs::write(
+        path.join(".ssh/id_ed25519.pub"),
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILoPdkEfhcsmW6Lg86GMrEJZnYfFBb7fL9G/IXK7pDQd",
+    )?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--inspect")
+        .arg("github-runner.token.age")
+        .arg("--rules")
+        .arg("does-not-exist.nix")
+        .env("HOME", &path)
+        .assert();
+    assert.success().stdout(formatdoc! {"
+        github-runner.token.age:
+          armored: yes
+          payload: 46 bytes (14 bytes plaintext)
+          3 recipient stanza(s):
+            X25519
+            ssh-ed25519 (key tag a6H7Ng): {}
+            ssh-rsa (key tag 1NDNnA)
+    ", path.join(".ssh/id_ed25519.pub").display()});
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--inspect")
+        .arg("secrets.nix")
+        .assert();
+    assert
+        .failure()
+        .stderr(predicate::str::contains("Not an age-encrypted file"));
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn check_headers_works() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd.current_dir(&path).arg("--check-headers").assert();
+    assert.success().stdout("");
+
+    // Replace the ssh-rsa key of the rules without rekeying
+    let rules = fs::read_to_string(path.join("secrets.nix"))?;
+    fs::write(
+        path.join("secrets.nix"),
+        rules.replace(
+            "publicKeys = [ age sshEd25519 sshRsa ];\n    extra",
+            "publicKeys = [ age sshEd25519 ];\n    extra",
+        ),
+    )?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd.current_dir(&path).arg("--check-headers").assert();
+    assert.failure().code(1).stdout(formatdoc! {"
+        {} is encrypted to an SSH key not in the rule (key tag 1NDNnA)
+        Run --rekey to re-encrypt the secrets
+    ", path.join("root.passwd.age").display()});
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn readable_by_works() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+
+    for key in [
+        "keys/id_ed25519",
+        "keys/key.txt",
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILoPdkEfhcsmW6Lg86GMrEJZnYfFBb7fL9G/IXK7pDQd wurzel@pfropf",
+    ] {
+        let mut cmd = Command::cargo_bin(crate_name!())?;
+        let assert = cmd
+            .current_dir(&path)
+            .arg("--readable-by")
+            .arg(key)
+            .assert();
+        assert
+            .success()
+            .stdout("github-runner.token.age\nroot.passwd.age\n");
+    }
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--readable-by")
+        .arg("age1fjc9tyguvxfqh2ey2qqfc066g3gee7hlnhqn2g7yn4f6smymmsnq6xdn2t")
+        .assert();
+    assert.success().stdout("");
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn accessible_works() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--accessible")
+        .arg("--identity")
+        .arg("keys/key.txt")
+        .env("HOME", &path)
+        .assert();
+    assert.success().stdout(indoc! {"
+        Identities:
+          keys/key.txt: age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk
+        Accessible secrets:
+          github-runner.token.age
+          root.passwd.age
+        Inaccessible secrets:
+    "});
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn access_matrix_works() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--access-matrix")
+        .arg("csv")
+        .assert();
+    assert.success().stdout(indoc! {"
+        secret,age,sshEd25519,sshRsa
+        github-runner.token.age,x,x,x
+        root.passwd.age,x,x,x
+    "});
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn config_works() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+    let config_home = path.join("xdg");
+    fs::create_dir_all(config_home.join("ragenix"))?;
+    fs::write(
+        config_home.join("ragenix/config.toml"),
+        formatdoc! {r#"
+            rules = "{}"
+            format = "dot"
+        "#, path.join("secrets.nix").display()},
+    )?;
+    fs::write(path.join("ragenix.toml"), "format = \"csv\"\n")?;
+
+    // The project configuration takes precedence over the user configuration
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(path.join("keys"))
+        .env("XDG_CONFIG_HOME", &config_home)
+        .env_remove("RULES")
+        .arg("--access-matrix")
+        .assert();
+    assert.success().stdout(indoc! {"
+        secret,age,sshEd25519,sshRsa
+        github-runner.token.age,x,x,x
+        root.passwd.age,x,x,x
+    "});
+
+    // Command line arguments take precedence over any configuration
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(path.join("keys"))
+        .env("XDG_CONFIG_HOME", &config_home)
+        .env_remove("RULES")
+        .arg("--access-matrix")
+        .arg("markdown")
+        .assert();
+    assert
+        .success()
+        .stdout(predicate::str::starts_with("| secret | age |"));
+
+    fs::write(path.join("ragenix.toml"), "rules = \"other.nix\"\n")?;
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .env("XDG_CONFIG_HOME", &config_home)
+        .arg("--access-matrix")
+        .assert();
+    assert
+        .failure()
+        .stderr(predicate::str::contains("must not set rules"));
+
+    Ok(())
+}
+
+#[test]
+fn command_line_turns_off_config_flags() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+    let config_home = path.join("xdg");
+    fs::create_dir_all(config_home.join("ragenix"))?;
+    fs::write(config_home.join("ragenix/config.toml"), "strict = true\n")?;
+    fs::write(
+        path.join("secrets.json"),
+        indoc! {r#"
+            {
+              "root.passwd.age": {
+                "publicKeys": [ "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk" ],
+                "extra": "Additional attributes are perfectly fine"
+              }
+            }
+        "#},
+    )?;
+
+    for (args, strict) in [
+        (&[][..], true),
+        (&["--no-strict"][..], false),
+        (&["--no-strict", "--strict"][..], true),
+    ] {
+        let mut cmd = Command::cargo_bin(crate_name!())?;
+        let assert = cmd
+            .current_dir(&path)
+            .env("XDG_CONFIG_HOME", &config_home)
+            .arg("--rules")
+            .arg("./secrets.json")
+            .args(args)
+            .arg("--readable-by")
+            .arg("keys/key.txt")
+            .assert();
+        if strict {
+            assert
+                .failure()
+                .stderr(predicate::str::contains("unknown attribute \"extra\""));
+        } else {
+            assert.success().stdout("root.passwd.age\n");
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn discovers_rules() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+    fs::create_dir(path.join(".git"))?;
+    let subdir = path.join("keys");
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&subdir)
+        .env("XDG_CONFIG_HOME", &path)
+        .env_remove("RULES")
+        .arg("--verbose")
+        .arg("--access-matrix")
+        .assert();
+    assert.success().stdout(predicate::str::starts_with(
+        "Using rules file ../secrets.nix\n",
+    ));
+
+    // Secret paths are relative to the working directory
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&subdir)
+        .env("XDG_CONFIG_HOME", &path)
+        .env_remove("RULES")
+        .arg("--edit")
+        .arg("../github-runner.token.age")
+        .arg("--identity")
+        .arg("id_ed25519")
+        .env("EDITOR", "true")
+        .assert();
+    assert.success().stdout(format!(
+        "{} wasn't changed, skipping re-encryption.\n",
+        path.join("github-runner.token.age").display()
+    ));
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn merges_rules_files() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+
+    fs::create_dir(path.join("hosts"))?;
+    fs::write(
+        path.join("hosts/secrets.nix"),
+        indoc! {r#"
+            {
+              "host.age".publicKeys = [ "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk" ];
+            }
+        "#},
+    )?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--rules")
+        .arg("./secrets.nix")
+        .arg("--rules")
+        .arg("./hosts")
+        .arg("--readable-by")
+        .arg("keys/key.txt")
+        .assert();
+    assert
+        .success()
+        .stdout("github-runner.token.age\nroot.passwd.age\nhosts/host.age\n");
+
+    fs::write(
+        path.join("hosts/secrets.nix"),
+        indoc! {r#"
+            {
+              "../root.passwd.age".publicKeys = [ "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk" ];
+            }
+        "#},
+    )?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--rules")
+        .arg(".")
+        .arg("--readable-by")
+        .arg("keys/key.txt")
+        .assert();
+    assert
+        .failure()
+        .stderr(predicate::str::contains(
+            "Several rules files define the same secrets",
+        ))
+        .stderr(predicate::str::contains("root.passwd.age"));
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn reads_rules_from_flake() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+
+    fs::write(
+        path.join("flake.nix"),
+        "{ outputs = _: { agenix.rules = import ./secrets.nix; }; }",
+    )?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(path.join("keys"))
+        .arg("--rules")
+        .arg(format!("path:{}#agenix.rules", path.display()))
+        .arg("--readable-by")
+        .arg("key.txt")
+        .assert();
+    assert
+        .success()
+        .stdout("github-runner.token.age\nroot.passwd.age\n");
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--rules")
+        .arg("github:wurzel/pfropf#agenix.rules")
+        .arg("--access-matrix")
+        .assert();
+    assert.failure().stderr(predicate::str::contains(
+        "Could not determine the root of the flake github:wurzel/pfropf",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn reads_json_and_toml_rules() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+
+    let json = indoc! {r#"
+        {
+          "github-runner.token.age": { "publicKeys": [ "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk" ] },
+          "root.passwd.age": { "publicKeys": [ "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk" ] }
+        }
+    "#};
+    fs::write(path.join("secrets.json"), json)?;
+    fs::write(
+        path.join("secrets.toml"),
+        indoc! {r#"
+            ["github-runner.token.age"]
+            publicKeys = [ "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk" ]
+        "#},
+    )?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--rules")
+        .arg("./secrets.json")
+        .arg("--readable-by")
+        .arg("keys/key.txt")
+        .assert();
+    assert
+        .success()
+        .stdout("github-runner.token.age\nroot.passwd.age\n");
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--rules")
+        .arg("./secrets.toml")
+        .arg("--access-matrix")
+        .arg("csv")
+        .assert();
+    assert.success().stdout(indoc! {"
+        secret,age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk
+        github-runner.token.age,x
+    "});
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--rules")
+        .arg("-")
+        .arg("--rekey")
+        .arg("--identity")
+        .arg("keys/key.txt")
+        .write_stdin(json)
+        .assert();
+    assert.success();
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--rules")
+        .arg("-")
+        .arg("--readable-by")
+        .arg("keys/key.txt")
+        .write_stdin("{ \"root.passwd.age\": { } }")
+        .assert();
+    assert
+        .failure()
+        .stderr(predicate::str::contains("secrets rules are invalid: '-'"));
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn caches_evaluated_rules() -> Result<()> {
+    let (dir, path) = copy_example_to_tmpdir()?;
+    let cache_home = dir.path().join("cache");
+
+    let access_matrix = || -> Result<_> {
+        let mut cmd = Command::cargo_bin(crate_name!())?;
+        Ok(cmd
+            .current_dir(&path)
+            .arg("--access-matrix")
+            .arg("csv")
+            .arg("--cache")
+            .env("XDG_CACHE_HOME", &cache_home)
+            .assert()
+            .success())
+    };
+
+    // Nothing is cached without --cache
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    cmd.current_dir(&path)
+        .arg("--access-matrix")
+        .env("XDG_CACHE_HOME", &cache_home)
+        .assert()
+        .success();
+    assert!(!cache_home.exists());
+
+    access_matrix()?;
+    let entries = fs::read_dir(cache_home.join("ragenix"))?.collect::<Vec<_>>();
+    assert_eq!(entries.len(), 1);
+
+    // A changed rules file is evaluated again
+    fs::write(
+        path.join("secrets.nix"),
+        indoc! {r#"
+            {
+              "root.passwd.age".publicKeys = [ "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk" ];
+            }
+        "#},
+    )?;
+    access_matrix()?.stdout(indoc! {"
+        secret,age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk
+        root.passwd.age,x
+    "});
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn passes_arguments_to_rules() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+
+    fs::write(
+        path.join("secrets.nix"),
+        indoc! {r#"
+            { key, count }:
+            {
+              "root.passwd.age".publicKeys = builtins.genList (_: key) count;
+            }
+        "#},
+    )?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--argstr")
+        .arg("key")
+        .arg("age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk")
+        .arg("--arg")
+        .arg("count")
+        .arg("1")
+        .arg("--verbose")
+        .arg("--readable-by")
+        .arg("keys/key.txt")
+        .assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains(
+            "Using nix arguments --arg count 1 --argstr key age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk\n",
+        ))
+        .stdout(predicate::str::ends_with("root.passwd.age\n"));
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--readable-by")
+        .arg("keys/key.txt")
+        .assert();
+    assert.failure();
+
+    Ok(())
+}
+
+#[test]
+fn reports_missing_evaluator() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--nix")
+        .arg("/nonexistent/bin/nix")
+        .arg("--readable-by")
+        .arg("keys/key.txt")
+        .assert();
+    assert.failure().stderr(predicate::str::contains(
+        "Failed to execute the Nix evaluator /nonexistent/bin/nix",
+    ));
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn evaluates_with_nix_instantiate() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--nix")
+        .arg("nix-instantiate")
+        .arg("--readable-by")
+        .arg("keys/key.txt")
+        .env("XDG_CACHE_HOME", path.join("cache"))
+        .assert();
+    assert
+        .success()
+        .stdout("github-runner.token.age\nroot.passwd.age\n");
+
+    Ok(())
+}
+
+#[test]
+#[cfg_attr(not(feature = "recursive-nix"), ignore)]
+fn restricts_evaluation_to_repository() -> Result<()> {
+    let (dir, path) = copy_example_to_tmpdir()?;
+
+    let readable_by = || -> Result<_> {
+        let mut cmd = Command::cargo_bin(crate_name!())?;
+        Ok(cmd
+            .current_dir(&path)
+            .arg("--restrict-eval")
+            .arg("--readable-by")
+            .arg("keys/key.txt")
+            .env("XDG_CACHE_HOME", path.join("cache"))
+            .assert())
+    };
+    readable_by()?
+        .success()
+        .stdout("github-runner.token.age\nroot.passwd.age\n");
+
+    // Files outside of the repository are off-limits
+    fs::write(dir.path().join("outside.nix"), "{ }")?;
+    fs::write(path.join("secrets.nix"), "import ../outside.nix")?;
+    readable_by()?
+        .failure()
+        .stderr(predicate::str::contains(
+            "error: secrets rules are invalid: './secrets.nix'",
+        ))
+        .stderr(predicate::str::contains(format!(
+            "access to absolute path '{}'",
+            dir.path().join("outside.nix").display()
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn rejects_unknown_attributes_when_strict() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+
+    fs::write(
+        path.join("secrets.json"),
+        indoc! {r#"
+            {
+              "github-runner.token.age": {
+                "publicKeys": [ "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk" ],
+                "publickeys": [ "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk" ]
+              },
+              "root.passwd.age": {
+                "publicKeys": [ "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk" ],
+                "armor": true,
+                "extra": "Additional attributes are perfectly fine"
+              }
+            }
+        "#},
+    )?;
+
+    // Unknown attributes are fine by default
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--rules")
+        .arg("./secrets.json")
+        .arg("--readable-by")
+        .arg("keys/key.txt")
+        .assert();
+    assert
+        .success()
+        .stdout("github-runner.token.age\nroot.passwd.age\n");
+
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--rules")
+        .arg("./secrets.json")
+        .arg("--strict")
+        .arg("--readable-by")
+        .arg("keys/key.txt")
+        .assert();
+    assert.failure().stderr(indoc! {r#"
+        error: secrets rules are invalid: './secrets.json'
+         - /github-runner.token.age/publickeys: unknown attribute "publickeys", did you mean "publicKeys"?
+         - /root.passwd.age/extra: unknown attribute "extra"
+    "#});
+
+    // agenix's own attributes are always known
+    fs::write(
+        path.join("secrets.json"),
+        indoc! {r#"
+            {
+              "root.passwd.age": {
+                "publicKeys": [ "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk" ],
+                "armor": true
+              }
+            }
+        "#},
+    )?;
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--rules")
+        .arg("./secrets.json")
+        .arg("--strict")
+        .arg("--readable-by")
+        .arg("keys/key.txt")
+        .assert();
+    assert.success().stdout(
+        "root.passwd.age
+",
+    );
+
+    Ok(())
+}
+
+#[test]
+fn reports_all_invalid_recipients() -> Result<()> {
+    let (_dir, path) = copy_example_to_tmpdir()?;
+    let age = "age1wl3fqfvyml0c5eaj00j0frad4vhspgx9t8sngq4342j7rzjw4pqs80euxk";
+    let ecdsa = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBAIr3+4g9Cg/gBsz81k48kpbPm5FUk5N3OjQ50uRVxW8gm2doKx51bpYHNod01scSNKDqBZWjVNVMmwgUphmKZo=";
+    // Without the `age-plugin-wurzel` binary
+    let plugin = "age1wurzel1qqqqqqqqqqqqq07zqhn";
+    fs::write(
+        path.join("secrets.json"),
+        serde_json::json!({
+            "github-runner.token.age": { "publicKeys": [ age, "age1wurzelpfropf" ] },
+            "root.passwd.age": { "publicKeys": [ ecdsa, age, plugin ] },
+        })
+        .to_string(),
+    )?;
+
+    let original = fs::read(path.join("root.passwd.age"))?;
+    let mut cmd = Command::cargo_bin(crate_name!())?;
+    let assert = cmd
+        .current_dir(&path)
+        .arg("--rules")
+        .arg("./secrets.json")
+        .arg("--rekey")
+        .arg("--identity")
+        .arg("keys/key.txt")
+        .assert();
+    assert.failure().stderr(indoc! {"
+        error: secrets rules are invalid: './secrets.json'
+         - /github-runner.token.age/publicKeys/1: invalid recipient, invalid age recipient, invalid bech32 encoding
+         - /root.passwd.age/publicKeys/0: invalid recipient, unsupported SSH key type ecdsa-sha2-nistp256
+         - /root.passwd.age/publicKeys/2: invalid recipient, unknown plugin wurzel, age-plugin-wurzel is not in PATH
+        Make sure you use an ssh-ed25519, ssh-rsa or an X25519 public key, alternatively install an age plugin which supports your key
+    "});
+    // Nothing was rekeyed
+    assert_eq!(fs::read(path.join("root.passwd.age"))?, original);
+
+    Ok(())
+}
+
 #[test]
 fn prints_schema() -> Result<()> {
     let mut cmd = Command::cargo_bin(crate_name!())?;
@@ -394,10 +1647,13 @@ fn rejects_invalid_rules() -> Result<()> {
         .env("EDITOR", "true")
         .assert();
 
-    assert.failure().stderr(indoc! {r#"
+    assert.failure().stderr(formatdoc! {r#"
             error: secrets rules are invalid: './secrets.nix'
              - /wurzel: "pfropf" is not of type "object"
-        "#});
+               at {}:1:3:
+                 1| {{ wurzel = "pfropf"; }}
+                  |   ^
+        "#, path.join("secrets.nix").display()});
 
     Ok(())
 }
@@ -425,7 +1681,9 @@ fn fails_for_invalid_recipient() -> Result<()> {
 
     assert
         .failure()
-        .stderr(predicate::str::contains(format!("Invalid recipient: {invalid_key}")))
+        .stderr(predicate::str::contains(
+            " - /wurzelpfropf.txt.age/publicKeys/0: invalid recipient, neither an age recipient nor an SSH public key",
+        ))
         .stderr(predicate::str::contains(
             "Make sure you use an ssh-ed25519, ssh-rsa or an X25519 public key, alternatively install an age plugin which supports your key",
         ));